@@ -0,0 +1,49 @@
+#![no_main]
+
+// Throw arbitrary bytes at the cart parser. It's allowed to give up with an
+// error, but it should never panic, and the spans it hands back should make
+// sense: in order, not overlapping, and inside the input. Whatever it does
+// parse should also rebuild into a cart that parses back the same way.
+
+use libfuzzer_sys::fuzz_target;
+use picoeater::{
+    include_tag, lua_tag, parse_sections, parse_sections_with_spans, rsc_tag, tab_name, Cart,
+    Section, DEFAULT_P8_VERSION,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(found) = parse_sections_with_spans(data) else {
+        return;
+    };
+    let mut end = 0;
+    for (section, span) in found.iter() {
+        assert!(span.start >= end && span.start <= span.end && span.end <= data.len());
+        end = span.end;
+        // Poke the line helpers with every line too, since a bad slice in
+        // one of those is exactly the kind of panic we're looking for.
+        let body = match section {
+            Section::Header { body, .. } => body,
+            Section::LuaTab { body, .. } => {
+                let _ = tab_name(body);
+                body
+            }
+            Section::Resource { body, .. } => body,
+        };
+        for line in body.lines() {
+            let _ = (lua_tag(line), rsc_tag(line), include_tag(line));
+        }
+    }
+    // And the plain version had better agree.
+    let plain = parse_sections(data).expect("parsed with spans, but not without");
+    assert!(plain.iter().eq(found.iter().map(|(section, _)| section)));
+
+    // Rebuild it. The header gets normalized (a cart with no version gets
+    // the default one), but the tabs and resources should come back exactly.
+    let cart = Cart::load(data).expect("parsed once, but not twice");
+    let mut rebuilt = Vec::new();
+    cart.write(&mut rebuilt).expect("writing to a Vec can't fail");
+    let reparsed = Cart::load(rebuilt.as_slice()).expect("rebuilt cart doesn't parse");
+    let mut expected = cart.clone();
+    expected.set_version(cart.version().unwrap_or(DEFAULT_P8_VERSION));
+    assert_eq!(reparsed, expected);
+});
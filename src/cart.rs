@@ -0,0 +1,178 @@
+// A whole cart you can poke at and write back out, for tools that want to
+// change one tab or resource without a round-trip through a directory.
+
+use std::io::{BufRead, Write};
+
+use crate::parse::{
+    parse_sections, tab_name, version_line, ParseError, Section, CART_HEADER, DEFAULT_P8_VERSION,
+};
+
+/// A parsed .p8 cart. Tab and resource bodies are whole lines, each ending
+/// in "\n", same as in `Section`.
+///
+/// Writing it back out works like a build: the header gets normalized to the
+/// standard two lines, and the tabs come before the resources. Everything
+/// else goes back exactly as it was.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cart {
+    version: Option<String>,
+    tabs: Vec<(Option<String>, String)>,
+    resources: Vec<(String, String)>,
+}
+
+impl Cart {
+    /// Read a cart.
+    pub fn load(reader: impl BufRead) -> Result<Self, ParseError> {
+        let mut cart = Self::default();
+        for section in parse_sections(reader)? {
+            match section {
+                Section::Header { version, .. } => cart.version = version,
+                Section::LuaTab { name, body } => cart.tabs.push((name, body)),
+                Section::Resource { kind, body } => cart.resources.push((kind, body)),
+            }
+        }
+        Ok(cart)
+    }
+
+    /// The cart's format version, if it has one.
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    pub fn set_version(&mut self, version: impl Into<String>) {
+        self.version = Some(version.into());
+    }
+
+    /// The names of the tabs, in order. (Tabs without a name comment come
+    /// back as None.)
+    pub fn tab_names(&self) -> impl Iterator<Item = Option<&str>> {
+        self.tabs.iter().map(|(name, _)| name.as_deref())
+    }
+
+    /// The body of the first tab with this name, name comment included.
+    pub fn tab(&self, name: &str) -> Option<&str> {
+        self.tabs
+            .iter()
+            .find(|(tab, _)| tab.as_deref() == Some(name))
+            .map(|(_, body)| body.as_str())
+    }
+
+    /// Replace the body of the tab with this name, or add it as a new last
+    /// tab. If the body doesn't start with a `-- name` comment for this name,
+    /// one gets added, so the tab keeps its name.
+    pub fn set_tab(&mut self, name: &str, body: &str) {
+        let mut text = String::new();
+        if tab_name(body) != Some(name) {
+            text.push_str(&format!("-- {}\n", name));
+        }
+        text.push_str(body);
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        match self
+            .tabs
+            .iter_mut()
+            .find(|(tab, _)| tab.as_deref() == Some(name))
+        {
+            Some((_, old)) => *old = text,
+            None => self.tabs.push((Some(name.to_string()), text)),
+        }
+    }
+
+    /// The kinds of the resource sections, in order.
+    pub fn resource_kinds(&self) -> impl Iterator<Item = &str> {
+        self.resources.iter().map(|(kind, _)| kind.as_str())
+    }
+
+    /// The body of a resource section, like `gfx`.
+    pub fn resource(&self, kind: &str) -> Option<&str> {
+        self.resources
+            .iter()
+            .find(|(found, _)| found == kind)
+            .map(|(_, body)| body.as_str())
+    }
+
+    /// Replace a resource section's body, or add it as a new last section.
+    pub fn set_resource(&mut self, kind: &str, body: &str) {
+        let mut text = body.to_string();
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        match self.resources.iter_mut().find(|(found, _)| found == kind) {
+            Some((_, old)) => *old = text,
+            None => self.resources.push((kind.to_string(), text)),
+        }
+    }
+
+    /// Write the cart out as a .p8, with LF line endings.
+    pub fn write(&self, mut writer: impl Write) -> std::io::Result<()> {
+        let version = self.version.as_deref().unwrap_or(DEFAULT_P8_VERSION);
+        writeln!(writer, "{}", CART_HEADER)?;
+        writeln!(writer, "{}", version_line(version))?;
+        writeln!(writer, "__lua__")?;
+        for (i, (_, body)) in self.tabs.iter().enumerate() {
+            if i > 0 {
+                writeln!(writer, "-->8")?;
+            }
+            writer.write_all(body.as_bytes())?;
+        }
+        for (kind, body) in self.resources.iter() {
+            writeln!(writer, "__{}__", kind)?;
+            writer.write_all(body.as_bytes())?;
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CART: &str = "pico-8 cartridge // http://www.pico-8.com
+version 41
+__lua__
+-- main
+print(\"hi\")
+-->8
+-- util
+function f() end
+__gfx__
+0000
+";
+
+    fn reload(cart: &Cart) -> Cart {
+        let mut bytes = Vec::new();
+        cart.write(&mut bytes).unwrap();
+        Cart::load(&bytes[..]).unwrap()
+    }
+
+    #[test]
+    fn writes_back_what_it_loaded() {
+        let cart = Cart::load(CART.as_bytes()).unwrap();
+        let mut bytes = Vec::new();
+        cart.write(&mut bytes).unwrap();
+        assert_eq!(String::from_utf8(bytes).unwrap(), CART);
+    }
+
+    #[test]
+    fn changes_survive_a_save_and_reload() {
+        let mut cart = Cart::load(CART.as_bytes()).unwrap();
+        cart.set_tab("util", "function g() end");
+        cart.set_tab("new", "-- new\nx=1\n");
+        cart.set_resource("gfx", "1111");
+        cart.set_resource("sfx", "00\n");
+        cart.set_version("38");
+
+        let cart = reload(&cart);
+        assert_eq!(cart.version(), Some("38"));
+        assert_eq!(
+            cart.tab_names().collect::<Vec<_>>(),
+            [Some("main"), Some("util"), Some("new")]
+        );
+        assert_eq!(cart.tab("util"), Some("-- util\nfunction g() end\n"));
+        assert_eq!(cart.tab("new"), Some("-- new\nx=1\n"));
+        assert_eq!(cart.resource_kinds().collect::<Vec<_>>(), ["gfx", "sfx"]);
+        assert_eq!(cart.resource("gfx"), Some("1111\n"));
+        assert_eq!(cart.resource("sfx"), Some("00\n"));
+    }
+}
@@ -0,0 +1,79 @@
+use anyhow::Context;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+use picoeater::Encoding;
+
+pub const CONFIG_FILE: &str = "picoeater.toml";
+
+/// Per-project defaults from a `picoeater.toml`. Keys mirror the CLI flags for
+/// each command, under `[build]` and `[dump]` tables. A flag passed on the
+/// command line always wins; anything not set in either place gets the
+/// built-in default.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub build: BuildConfig,
+    pub dump: DumpConfig,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct BuildConfig {
+    pub warn_mixed_eol: bool,
+    pub crlf: bool,
+    pub warn_tab_count: Option<usize>,
+    pub warn_tab_lines: Option<usize>,
+    pub strict: bool,
+    pub include_empty_sections: bool,
+    pub include_tabs: Vec<String>,
+    pub exclude_tabs: Vec<String>,
+    pub sort_resources: bool,
+    pub validate: bool,
+    pub verify: bool,
+    pub encoding: Option<Encoding>,
+    pub trim_trailing_whitespace: bool,
+    pub post_build: Option<String>,
+    pub ignore_hook_failure: bool,
+    pub stamp: bool,
+    pub module_tabs: bool,
+    pub no_version_file: bool,
+    pub assume_version: Option<String>,
+    pub cart_name: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DumpConfig {
+    pub no_synth_names: bool,
+    pub canonical_names: bool,
+    pub fsync: bool,
+    pub strip_name_comment: bool,
+    pub no_clobber: bool,
+    pub dest_must_exist: bool,
+    pub dedupe_tabs: bool,
+    pub strict: bool,
+    pub only_if_changed: bool,
+    pub no_version_file: bool,
+    pub binary_sections: Vec<String>,
+    pub exclude_sections: Vec<String>,
+    pub encoding: Option<Encoding>,
+}
+
+/// Find the nearest `picoeater.toml` in the given directory or any of its
+/// parents.
+pub fn find(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|d| d.join(CONFIG_FILE))
+        .find(|path| path.is_file())
+}
+
+/// Load the nearest config file, or the defaults if there isn't one.
+pub fn load(dir: &Path) -> anyhow::Result<Config> {
+    let Some(path) = find(dir) else {
+        return Ok(Config::default());
+    };
+    let text = std::fs::read_to_string(&path)
+        .with_context(|| format!("Couldn't read {}", path.to_string_lossy()))?;
+    toml::from_str(&text).with_context(|| format!("Couldn't parse {}", path.to_string_lossy()))
+}
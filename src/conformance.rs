@@ -0,0 +1,193 @@
+// The round-trip promises from the README's "What round-trips exactly"
+// section, checked against a pile of in-memory carts. Exact carts have to
+// come back from dump → build byte-for-byte. Lossy ones are allowed to change
+// on the first trip (each says why), but never again after that.
+
+use crate::tests::round_trip;
+
+const HEADER: &str = "pico-8 cartridge // http://www.pico-8.com\nversion 41\n";
+
+/// A full-width row of hex, for resource sections that should look real.
+fn row(width: usize, digit: char) -> String {
+    let mut row: String = std::iter::repeat_n(digit, width).collect();
+    row.push('\n');
+    row
+}
+
+/// Carts that come back byte-for-byte on the very first trip.
+fn exact_carts() -> Vec<(&'static str, String)> {
+    let gfx = row(128, '0') + &row(128, '7') + &row(128, 'a');
+    let label = row(128, 'v') + &row(128, '0');
+    vec![
+        ("minimal", format!("{HEADER}__lua__\n-- main\nprint(1)\n")),
+        (
+            "every kind of section",
+            format!(
+                "{HEADER}__lua__\n-- main\nfunction _init()\nend\n-->8\n-- player\nx=1\n__gfx__\n{gfx}__gff__\n{}__label__\n{label}\n__map__\n{}__sfx__\n{}__music__\n00 41424344\n",
+                row(256, '0'),
+                row(256, '1'),
+                row(168, '0'),
+            ),
+        ),
+        (
+            "no resources at all",
+            format!("{HEADER}__lua__\n-- a\nx=1\n-->8\n-- b\ny=2\n"),
+        ),
+        (
+            "PICO-8 glyphs",
+            format!("{HEADER}__lua__\n-- 🐱 cat\nprint(\"█▒🐱\")\n-->8\n-- ⬅️ and ➡️\n?\"⌂\"\n"),
+        ),
+        (
+            "blank lines above the name",
+            format!("{HEADER}__lua__\n\n\n-- main\nprint(1)\n-->8\n  \n-- other\nx=1\n"),
+        ),
+        (
+            "block comment names",
+            format!("{HEADER}__lua__\n--[[ main ]]\nprint(1)\n-->8\n--[[ util ]]\nx=1\n"),
+        ),
+        (
+            "resources out of the usual order",
+            format!("{HEADER}__lua__\n-- main\nx=1\n__sfx__\n{}__gfx__\n{gfx}", row(168, '1')),
+        ),
+        (
+            "an unknown section",
+            format!("{HEADER}__lua__\n-- main\nx=1\n__meta:editor__\nanything goes = here\n"),
+        ),
+        (
+            "names with spaces",
+            format!("{HEADER}__lua__\n-- main loop\nx=1\n-->8\n-- the end\ny=2\n"),
+        ),
+        (
+            "lines that only look like tags",
+            format!("{HEADER}__lua__\n-- main\n s=\"__gfx__\"\n-- -->8\n__gfx __\n"),
+        ),
+        (
+            "a tab named like a file it can't be",
+            format!("{HEADER}__lua__\n-- main\nx=1\n-->8\n-- ../escape\ny=2\n"),
+        ),
+        (
+            "lots of tabs",
+            format!(
+                "{HEADER}__lua__\n{}",
+                (0..16)
+                    .map(|i| format!("-- tab{i}\nx{i}={i}\n"))
+                    .collect::<Vec<_>>()
+                    .join("-->8\n")
+            ),
+        ),
+    ]
+}
+
+#[test]
+fn exact_carts_round_trip_exactly() {
+    for (name, cart) in exact_carts() {
+        let rebuilt = round_trip(cart.as_bytes(), &[], &[]);
+        assert_eq!(String::from_utf8_lossy(&rebuilt), cart, "{name}");
+    }
+}
+
+#[test]
+fn exact_carts_survive_the_dump_options_too() {
+    for (name, cart) in exact_carts() {
+        for flags in [
+            &["--strip-name-comment"][..],
+            &["--binary-sections", "gfx,gff,map,sfx"],
+            &["--no-synth-names"],
+            &["--encoding", "bytes"],
+        ] {
+            let rebuilt = round_trip(cart.as_bytes(), flags, &[]);
+            assert_eq!(String::from_utf8_lossy(&rebuilt), cart, "{name} {flags:?}");
+        }
+    }
+}
+
+#[test]
+fn non_utf8_bytes_round_trip_with_encoding_bytes() {
+    let mut cart = format!("{HEADER}__lua__\n-- main\nprint(\"").into_bytes();
+    cart.extend_from_slice(b"\xe9\xff\x80");
+    cart.extend_from_slice(b"\")\n");
+    let flags = ["--encoding", "bytes"];
+    assert_eq!(round_trip(&cart, &flags, &flags), cart);
+}
+
+/// Carts that change on the first trip, and why. Each one has to settle
+/// down after that.
+fn lossy_carts() -> Vec<(&'static str, String)> {
+    vec![
+        (
+            "anonymous tabs get an unknown-NN name comment",
+            format!("{HEADER}__lua__\nprint(1)\n-->8\nx=1\n"),
+        ),
+        (
+            "the header gets normalized",
+            "pico-8 cartridge // http://www.pico-8.com\nversion 8\nsome junk\n__lua__\n-- main\nx=1\n"
+                .to_string(),
+        ),
+        (
+            "a missing version gets the default",
+            "pico-8 cartridge // http://www.pico-8.com\n__lua__\n-- main\nx=1\n".to_string(),
+        ),
+        (
+            "CRLF becomes LF",
+            format!("{HEADER}__lua__\n-- main\nx=1\n").replace('\n', "\r\n"),
+        ),
+        (
+            "bare CR becomes LF",
+            format!("{HEADER}__lua__\n-- main\nx=1\n").replace('\n', "\r"),
+        ),
+        (
+            "the last line gets a newline",
+            format!("{HEADER}__lua__\n-- main\nx=1"),
+        ),
+        (
+            "empty resource sections get dropped",
+            format!("{HEADER}__lua__\n-- main\nx=1\n__gfx__\n__map__\n00\n"),
+        ),
+        (
+            "lua moves in front of the resources",
+            "pico-8 cartridge // http://www.pico-8.com\nversion 41\n__gfx__\n00\n__lua__\n-- main\nx=1\n"
+                .to_string(),
+        ),
+        (
+            "an empty middle tab gets a name",
+            format!("{HEADER}__lua__\n-- a\nx=1\n-->8\n-->8\n-- c\ny=2\n"),
+        ),
+        (
+            "an empty last tab gets a name",
+            format!("{HEADER}__lua__\n-- a\nx=1\n-->8\n__gfx__\n00\n"),
+        ),
+        (
+            "a repeated resource section keeps only the last one",
+            format!("{HEADER}__lua__\n-- main\nx=1\n__gfx__\n00\n__gfx__\n11\n"),
+        ),
+        (
+            "a second __lua__ becomes a -->8",
+            format!("{HEADER}__lua__\n-- a\nx=1\n__lua__\n-- b\ny=2\n"),
+        ),
+    ]
+}
+
+#[test]
+fn lossy_carts_settle_after_one_trip() {
+    for (why, cart) in lossy_carts() {
+        let first = round_trip(cart.as_bytes(), &[], &[]);
+        assert_ne!(first, cart.as_bytes(), "{why}: didn't change at all");
+        let second = round_trip(&first, &[], &[]);
+        assert_eq!(
+            String::from_utf8_lossy(&second),
+            String::from_utf8_lossy(&first),
+            "{why}"
+        );
+    }
+}
+
+#[test]
+fn empty_tabs_go_away_without_synth_names() {
+    // With no name to give it, an empty tab is an empty file, and build
+    // leaves those out. After that, it's stable.
+    let cart = format!("{HEADER}__lua__\n-- a\nx=1\n-->8\n-->8\n-- c\ny=2\n");
+    let first = round_trip(cart.as_bytes(), &["--no-synth-names"], &[]);
+    let expected = format!("{HEADER}__lua__\n-- a\nx=1\n-->8\n-- c\ny=2\n");
+    assert_eq!(String::from_utf8_lossy(&first), expected);
+    assert_eq!(round_trip(&first, &["--no-synth-names"], &[]), first);
+}
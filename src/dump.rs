@@ -0,0 +1,938 @@
+// Dumping a cart: splitting it into a directory of component files that a
+// build can put back together.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::encoding::{Encoding, EncodingError};
+use crate::parse::{
+    parse_sections, parse_sections_traced, rsc_tag, ParseError, Section, DEFAULT_P8_VERSION,
+};
+use crate::sections;
+
+/// The order the tabs go in, one name per line.
+pub const TAB_ORDER_FILE: &str = "_tab_order.p8meta";
+/// The order the resources go in, one kind per line.
+pub const RSC_ORDER_FILE: &str = "_rsc_order.p8meta";
+/// The cart's format version.
+pub const P8_VERSION_FILE: &str = "_version.p8meta";
+/// Tabs whose name comment got left out of their file.
+pub const STRIPPED_FILE: &str = "_stripped.p8meta";
+
+#[derive(thiserror::Error, Debug)]
+pub enum DumpError {
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    #[error(transparent)]
+    Encoding(#[from] EncodingError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Splits a cart into component files in a directory: one `.lua` per tab,
+/// one `.p8rsc` (or `.p8bin`) per resource, and the meta files that let a
+/// build put it all back together.
+pub struct P8Dumper<R> {
+    reader: R,
+    dest: PathBuf,
+    options: DumpOptions,
+}
+
+/// Knobs for how a dump behaves. The default is what the CLI does with no
+/// flags.
+#[derive(Debug, Clone)]
+pub struct DumpOptions {
+    /// Whether to write a `-- unknown-NN` first line into anonymous tabs.
+    pub synth_names: bool,
+    /// Whether anonymous tabs get named by content hash instead of position.
+    pub canonical_names: bool,
+    /// Whether to sync each file to disk before we're done.
+    pub fsync: bool,
+    /// Whether identical tabs share one file.
+    pub dedupe_tabs: bool,
+    /// Whether to leave name comments out of the tab files.
+    pub strip_name_comment: bool,
+    /// Resource kinds to write as raw bytes instead of hex text.
+    pub binary_sections: Vec<String>,
+    /// Resource kinds to skip writing (but still list in the order file).
+    pub exclude_sections: Vec<String>,
+    /// How to read the cart and write the files.
+    pub encoding: Encoding,
+    /// Whether to leave files alone when they already say the right thing.
+    pub only_if_changed: bool,
+    /// Whether to skip writing _version.p8meta.
+    pub no_version_file: bool,
+    /// Whether to print the parser's every move to stderr.
+    pub trace_sections: bool,
+    /// The version builds will use if we don't write one, for warnings.
+    pub fallback_version: String,
+}
+
+impl Default for DumpOptions {
+    fn default() -> Self {
+        Self {
+            synth_names: true,
+            canonical_names: false,
+            fsync: false,
+            dedupe_tabs: false,
+            strip_name_comment: false,
+            binary_sections: Vec::new(),
+            exclude_sections: Vec::new(),
+            encoding: Encoding::default(),
+            only_if_changed: false,
+            no_version_file: false,
+            trace_sections: false,
+            fallback_version: DEFAULT_P8_VERSION.to_string(),
+        }
+    }
+}
+
+/// What a dump did.
+#[derive(Debug)]
+pub struct DumpResults {
+    /// The tabs, in cart order, by the names their files got.
+    pub tab_order: Vec<String>,
+    /// The resource kinds, in cart order.
+    pub rsc_order: Vec<String>,
+    /// Everything worth mentioning, in the order it came up.
+    pub warnings: Vec<DumpWarning>,
+    /// The files the dump actually wrote, and how big they are. (With
+    /// --only-if-changed, unchanged files don't count.)
+    pub written: Vec<(PathBuf, u64)>,
+    /// Every file the dump produced, written or not, in order.
+    pub outputs: Vec<PathBuf>,
+    /// Files the dump made sure aren't there anymore (like the other format
+    /// of a resource), whether or not they were there to begin with.
+    pub cleared: Vec<PathBuf>,
+}
+
+/// Something a dump noticed about the cart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DumpWarning {
+    /// The header has no version line. (Holds the version builds will use.)
+    NoVersion(String),
+    /// The header's version line isn't a number.
+    BadVersion { line: String, fallback: String },
+    /// A tab's name can't be a filename, so it went in a fallback file.
+    UnsafeTabName {
+        index: usize,
+        name: String,
+        problem: &'static str,
+        fallback: String,
+    },
+    /// A tab had no name, so it got one made up.
+    SynthesizedName { index: usize, name: String },
+    /// A tab's name was already taken, so its file got a different one.
+    NameCollision { name: String, renamed: String },
+    /// Two resource kinds that only differ by case.
+    CaseCollision { first: String, second: String },
+    /// A resource that was supposed to be binary but couldn't be.
+    NotBinary(String),
+    /// The last resource has a short last row.
+    Truncated {
+        kind: String,
+        row: usize,
+        width: usize,
+    },
+}
+
+impl DumpWarning {
+    /// Whether this is worth bothering someone about. The rest are normal
+    /// enough that the CLI only puts them in the report.
+    pub fn is_loud(&self) -> bool {
+        !matches!(
+            self,
+            DumpWarning::SynthesizedName { .. } | DumpWarning::NameCollision { .. }
+        )
+    }
+}
+
+impl std::fmt::Display for DumpWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DumpWarning::NoVersion(fallback) => write!(
+                f,
+                "No version header found; rebuilds will use the default version {}.",
+                fallback
+            ),
+            DumpWarning::BadVersion { line, fallback } => write!(
+                f,
+                "The header's version line ({}) isn't a number; rebuilds will use the default version {}.",
+                line, fallback
+            ),
+            DumpWarning::UnsafeTabName {
+                index,
+                name,
+                problem,
+                fallback,
+            } => write!(
+                f,
+                "Tab {} is named '{}', which {}; writing it to {}.lua instead.",
+                index, name, problem, fallback
+            ),
+            DumpWarning::SynthesizedName { index, name } => {
+                write!(f, "Tab {} has no name; calling it {}.", index, name)
+            }
+            DumpWarning::NameCollision { name, renamed } => write!(
+                f,
+                "There's already a tab named {}; writing this one to {}.lua.",
+                name, renamed
+            ),
+            DumpWarning::CaseCollision { first, second } => write!(
+                f,
+                "The cart has both a {} and a {} section; on a case-insensitive filesystem, one will overwrite the other.",
+                first, second
+            ),
+            DumpWarning::NotBinary(kind) => write!(
+                f,
+                "Can't store the {} section as binary without losing something; writing {}.p8rsc instead.",
+                kind, kind
+            ),
+            DumpWarning::Truncated { kind, row, width } => write!(
+                f,
+                "The last row of the {} section is only {} of {} characters wide; the cart looks truncated.",
+                kind, row, width
+            ),
+        }
+    }
+}
+
+impl P8Dumper<BufReader<File>> {
+    /// Make a new P8Reader from a provided absolute file path and dir path.
+    pub fn new(
+        path: impl AsRef<Path>,
+        dest: PathBuf,
+        options: DumpOptions,
+    ) -> std::io::Result<Self> {
+        File::open(path).map(|file| Self::from_reader(BufReader::new(file), dest, options))
+    }
+}
+
+impl<R: BufRead> P8Dumper<R> {
+    /// Make a new P8Reader that reads the cart from anywhere (a pipe, a
+    /// buffer in memory, whatever), and writes to the provided dir path.
+    pub fn from_reader(reader: R, dest: PathBuf, options: DumpOptions) -> Self {
+        Self {
+            reader,
+            dest,
+            options,
+        }
+    }
+
+    /// Do the dump. `on_warning` hears about each warning as it comes up
+    /// (they're all in the results too, in case you'd rather look later).
+    pub fn dump(self, mut on_warning: impl FnMut(&DumpWarning)) -> Result<DumpResults, DumpError> {
+        // consume self
+        let Self {
+            mut reader,
+            dest,
+            options,
+        } = self;
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let text = options.encoding.cart().decode(bytes)?;
+        let sections = if options.trace_sections {
+            parse_sections_traced(text.as_bytes(), |step| eprintln!("{}", step))?
+        } else {
+            parse_sections(text.as_bytes())?
+        };
+        let mut ctx = DumpContext::new(dest, options);
+        for section in sections.iter() {
+            match section {
+                Section::Header { version, body } => {
+                    debug!("header says version {:?}", version);
+                    if ctx.options.no_version_file {
+                        debug!("not writing {}", P8_VERSION_FILE);
+                    } else if let Some(ver) = version {
+                        let mut writer = ctx.make_writer(P8_VERSION_FILE);
+                        writer.write_all(ver.as_bytes())?;
+                        ctx.finish_writer(writer)?;
+                    } else {
+                        // Only a number counts as a version. Something like
+                        // `version beta` might not load in PICO-8, so it
+                        // doesn't get stored or passed along.
+                        let bad_line = body
+                            .lines()
+                            .map(str::trim)
+                            .find(|line| line.starts_with("version"));
+                        let warning = match bad_line {
+                            Some(line) => DumpWarning::BadVersion {
+                                line: line.to_string(),
+                                fallback: ctx.options.fallback_version.clone(),
+                            },
+                            None => DumpWarning::NoVersion(ctx.options.fallback_version.clone()),
+                        };
+                        ctx.warn(warning, &mut on_warning);
+                        // Don't let a version from some earlier dump stick around and
+                        // make a liar out of that warning.
+                        ctx.clear(P8_VERSION_FILE)?;
+                    }
+                }
+                Section::LuaTab { name, body } => {
+                    ctx.write_tab(name.as_deref(), body, &mut on_warning)?;
+                }
+                Section::Resource { kind, body } => {
+                    let _section = section_span!("rsc", kind);
+                    ctx.write_rsc(kind, body, &mut on_warning)?;
+                }
+            }
+        }
+        // A cart that got cut off partway through its last section still
+        // parses fine, so look for a ragged last row.
+        if let Some(Section::Resource { kind, body }) = sections.last() {
+            if let Some(info) = sections::lookup(kind) {
+                if let Some(last) = body.lines().rev().find(|row| !row.is_empty()) {
+                    if last.chars().count() < info.width {
+                        ctx.warn(
+                            DumpWarning::Truncated {
+                                kind: kind.clone(),
+                                row: last.chars().count(),
+                                width: info.width,
+                            },
+                            &mut on_warning,
+                        );
+                    }
+                }
+            }
+        }
+        Ok(ctx.finish()?)
+    }
+}
+
+/// A component or meta file that's still being put together in memory.
+struct PendingFile {
+    path: PathBuf,
+    bytes: Vec<u8>,
+}
+
+impl PendingFile {
+    /// Add a line, ending in LF.
+    fn write_line(&mut self, line: &[u8]) -> std::io::Result<()> {
+        self.write_all(line)?;
+        self.write_all(b"\n")
+    }
+}
+
+impl Write for PendingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.bytes.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The dump state that outlives any one section: where the files go, and
+/// which ones we've written so far.
+struct DumpContext {
+    dest: PathBuf,
+    options: DumpOptions,
+    /// Index of the next tab, for naming anonymous ones.
+    lua_index: usize,
+    // Keep track of which files we wrote to in which order. We use this for builds,
+    // and for purging.
+    tab_order: Vec<String>,
+    rsc_order: Vec<String>,
+    /// For --dedupe-tabs: the contents of each tab file written so far, and
+    /// which tab it belongs to.
+    tab_contents: HashMap<String, String>,
+    /// Tabs that didn't get a file, and the tab whose file they reuse.
+    tab_aliases: HashMap<String, String>,
+    /// Tab files that had their name comment taken out.
+    stripped: Vec<String>,
+    warnings: Vec<DumpWarning>,
+    written: Vec<(PathBuf, u64)>,
+    outputs: Vec<PathBuf>,
+    cleared: Vec<PathBuf>,
+}
+
+impl DumpContext {
+    fn new(dest: PathBuf, options: DumpOptions) -> Self {
+        Self {
+            dest,
+            options,
+            lua_index: 0,
+            tab_order: Vec::new(),
+            rsc_order: Vec::new(),
+            tab_contents: HashMap::new(),
+            tab_aliases: HashMap::new(),
+            stripped: Vec::new(),
+            warnings: Vec::new(),
+            written: Vec::new(),
+            outputs: Vec::new(),
+            cleared: Vec::new(),
+        }
+    }
+
+    /// Keep track of a warning, and print it if it's worth printing.
+    fn warn(&mut self, warning: DumpWarning, on_warning: &mut dyn FnMut(&DumpWarning)) {
+        on_warning(&warning);
+        self.warnings.push(warning);
+    }
+
+    /// Start a file. Nothing hits the disk until `finish_writer`.
+    fn make_writer(&self, filename: &str) -> PendingFile {
+        PendingFile {
+            path: self.dest.join(filename),
+            bytes: Vec::new(),
+        }
+    }
+
+    /// Turn some text into bytes for a component or meta file.
+    fn encode<'a>(&self, text: &'a str) -> std::io::Result<std::borrow::Cow<'a, [u8]>> {
+        Ok(self.options.encoding.files().encode(text)?)
+    }
+
+    /// Write out a file we're done with, and sync it to disk if asked. With
+    /// --only-if-changed, a file that already has these exact bytes is left
+    /// alone.
+    fn finish_writer(&mut self, writer: PendingFile) -> std::io::Result<()> {
+        let PendingFile { path, bytes } = writer;
+        self.outputs.push(path.clone());
+        if self.options.only_if_changed && std::fs::read(&path).is_ok_and(|old| old == bytes) {
+            debug!("{} is unchanged", path.display());
+            return Ok(());
+        }
+        let mut file = File::create(&path)?;
+        file.write_all(&bytes)?;
+        if self.options.fsync {
+            file.sync_all()?;
+        }
+        self.written.push((path, bytes.len() as u64));
+        Ok(())
+    }
+
+    /// Delete a file if it's there, and keep track of it either way.
+    fn clear(&mut self, filename: &str) -> std::io::Result<()> {
+        let path = self.dest.join(filename);
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        self.cleared.push(path);
+        Ok(())
+    }
+
+    /// Pick a filename for a tab based on its name comment (if any), and
+    /// write it out (plus a name comment, if we had to make one up).
+    fn write_tab(
+        &mut self,
+        maybe_name: Option<&str>,
+        body: &str,
+        on_warning: &mut dyn FnMut(&DumpWarning),
+    ) -> std::io::Result<()> {
+        let mut name = match maybe_name {
+            Some(tag) => match tab_name_problem(tag) {
+                None => tag.to_string(),
+                Some(problem) => {
+                    // Its name comment stays in the file as-is, so the
+                    // cart still comes back the same; it's just the
+                    // filename that has to change.
+                    let fallback = format!("unknown-{:02}", self.lua_index);
+                    self.warn(
+                        DumpWarning::UnsafeTabName {
+                            index: self.lua_index,
+                            name: tag.to_string(),
+                            problem,
+                            fallback: fallback.clone(),
+                        },
+                        on_warning,
+                    );
+                    fallback
+                }
+            },
+            None => {
+                // With --canonical-names, the name sticks with the tab's
+                // contents instead of its position.
+                let name = if self.options.canonical_names {
+                    format!("tab-{:08x}", fnv1a(body.as_bytes()))
+                } else {
+                    format!("unknown-{:02}", self.lua_index)
+                };
+                self.warn(
+                    DumpWarning::SynthesizedName {
+                        index: self.lua_index,
+                        name: name.clone(),
+                    },
+                    on_warning,
+                );
+                name
+            }
+        };
+        // If there's a name collision, do something gross to avoid calamity.
+        if self.tab_order.contains(&name) {
+            let taken = name.clone();
+            while self.tab_order.contains(&name) {
+                name.push_str("-again");
+            }
+            self.warn(
+                DumpWarning::NameCollision {
+                    name: taken,
+                    renamed: name.clone(),
+                },
+                on_warning,
+            );
+        }
+        let _section = section_span!("lua", name);
+        let filename = format!("{}.lua", &name);
+        // If we didn't get a name from the initial line, guess what:
+        // we'll damn well get one next time :] This makes THIS round-trip
+        // inexact, but it should help keep subsequent round-trips more stable.
+        // (Unless they asked us not to, in which case the tab stays anonymous
+        // and its unknown-NN name only lives in the filename and tab order.)
+        let mut contents = String::new();
+        if maybe_name.is_none() && self.options.synth_names {
+            contents.push_str(&format!("-- {}\n", &name));
+        }
+        contents.push_str(body);
+        if self.options.dedupe_tabs {
+            if let Some(original) = self.tab_contents.get(&contents) {
+                debug!("tab {} is a repeat of {}", name, original);
+                self.tab_aliases.insert(name.clone(), original.clone());
+                // Don't leave an old copy around for build to trip over.
+                self.clear(&filename)?;
+                self.tab_order.push(name);
+                self.lua_index += 1;
+                return Ok(());
+            }
+        }
+        debug!("writing tab to {}", filename);
+        trace!("{}", body);
+        let mut writer = self.make_writer(&filename);
+        // With --strip-name-comment, the file only gets the code, as long as
+        // the first line is exactly what build would put back.
+        let name_line = format!("-- {}\n", &name);
+        match contents.strip_prefix(&name_line) {
+            Some(code) if self.options.strip_name_comment => {
+                writer.write_all(&self.encode(code)?)?;
+                self.stripped.push(name.clone());
+            }
+            _ => writer.write_all(&self.encode(&contents)?)?,
+        }
+        self.finish_writer(writer)?;
+        if self.options.dedupe_tabs {
+            self.tab_contents.insert(contents, name.clone());
+        }
+        // Save the script name to tab order
+        self.tab_order.push(name);
+        // bump the index for next time
+        self.lua_index += 1;
+        Ok(())
+    }
+
+    /// Write out a resource section, as hex text or (if asked) raw bytes.
+    fn write_rsc(
+        &mut self,
+        kind: &str,
+        body: &str,
+        on_warning: &mut dyn FnMut(&DumpWarning),
+    ) -> std::io::Result<()> {
+        if self.options.exclude_sections.iter().any(|k| k == kind) {
+            // Keep its place in line, so a later full dump (or a build from
+            // an old one) still puts it in the right spot.
+            debug!("skipping excluded resource {}", kind);
+            self.rsc_order.push(kind.to_string());
+            return Ok(());
+        }
+        // Kinds keep their case exactly, but on a case-insensitive filesystem
+        // (hi, macOS and Windows) `GFX.p8rsc` and `gfx.p8rsc` are the same file.
+        if let Some(other) = self
+            .rsc_order
+            .iter()
+            .find(|other| other.as_str() != kind && other.eq_ignore_ascii_case(kind))
+        {
+            let warning = DumpWarning::CaseCollision {
+                first: other.clone(),
+                second: kind.to_string(),
+            };
+            self.warn(warning, on_warning);
+        }
+        let binary = if self.options.binary_sections.iter().any(|k| k == kind) {
+            let bytes = sections::lookup(kind)
+                .filter(|info| info.hex)
+                .and_then(|info| sections::hex_to_bytes(body, info.width));
+            if bytes.is_none() {
+                self.warn(DumpWarning::NotBinary(kind.to_string()), on_warning);
+            }
+            bytes
+        } else {
+            None
+        };
+        let (filename, stale) = if binary.is_some() {
+            (format!("{}.p8bin", kind), format!("{}.p8rsc", kind))
+        } else {
+            (format!("{}.p8rsc", kind), format!("{}.p8bin", kind))
+        };
+        // Only one copy of a resource per dir, or build wouldn't know which to use.
+        self.clear(&stale)?;
+        debug!("writing resource to {}", filename);
+        trace!("{}", body);
+        let mut writer = self.make_writer(&filename);
+        match binary {
+            Some(bytes) => writer.write_all(&bytes)?,
+            None => writer.write_all(&self.encode(body)?)?,
+        }
+        self.finish_writer(writer)?;
+        // also stash the kind to resource order
+        self.rsc_order.push(kind.to_string());
+        Ok(())
+    }
+
+    /// Write the tab order and resource order, and hand back the results.
+    fn finish(mut self) -> std::io::Result<DumpResults> {
+        debug!("tab order: {:?}", self.tab_order);
+        debug!("resource order: {:?}", self.rsc_order);
+        let mut tab_writer = self.make_writer(TAB_ORDER_FILE);
+        for name in self.tab_order.iter() {
+            // A repeated tab gets listed as "name<TAB>original".
+            let line = match self.tab_aliases.get(name) {
+                Some(original) => format!("{}\t{}", name, original),
+                None => name.clone(),
+            };
+            tab_writer.write_line(&self.encode(&line)?)?;
+        }
+        self.finish_writer(tab_writer)?;
+        if self.stripped.is_empty() {
+            // An old list from some earlier dump would be wrong now.
+            self.clear(STRIPPED_FILE)?;
+        } else {
+            let mut stripped_writer = self.make_writer(STRIPPED_FILE);
+            for name in self.stripped.iter() {
+                stripped_writer.write_line(&self.encode(name)?)?;
+            }
+            self.finish_writer(stripped_writer)?;
+        }
+        let mut rsc_writer = self.make_writer(RSC_ORDER_FILE);
+        for line in self.rsc_order.iter() {
+            rsc_writer.write_line(&self.encode(line)?)?;
+        }
+        self.finish_writer(rsc_writer)?;
+        // New directory entries aren't durable until the directory is synced too.
+        // (Only possible on unix; Windows won't open a directory as a file.)
+        #[cfg(unix)]
+        if self.options.fsync {
+            File::open(&self.dest)?.sync_all()?;
+        }
+        Ok(DumpResults {
+            tab_order: self.tab_order,
+            rsc_order: self.rsc_order,
+            warnings: self.warnings,
+            written: self.written,
+            outputs: self.outputs,
+            cleared: self.cleared,
+        })
+    }
+}
+
+/// If a tab's name can't be used as its filename, says why. That's names that
+/// would look like cart structure in the tab order, or escape the directory,
+/// or get skipped as a hidden or temp file when building. (Carts come from
+/// all over, so some of these are surely on purpose.)
+pub fn tab_name_problem(name: &str) -> Option<&'static str> {
+    if name == "-->8" || name == "__lua__" || rsc_tag(name).is_some() {
+        Some("looks like a section marker")
+    } else if name == ".." || name.contains(['/', '\\']) || name.contains(|c: char| c.is_control())
+    {
+        Some("isn't safe to use as a filename")
+    } else if is_hidden_or_temp(OsStr::new(&format!("{}.lua", name))) {
+        Some("would make a hidden or temp file")
+    } else {
+        None
+    }
+}
+
+/// 32-bit FNV-1a, for --canonical-names. Not fancy, but it's stable across
+/// platforms and Rust versions, which the std hasher isn't.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, &b| {
+        (hash ^ u32::from(b)).wrapping_mul(0x01000193)
+    })
+}
+
+/// Dotfiles, backups, swap files, and the like, which should never count as
+/// component files.
+pub fn is_hidden_or_temp(name: &OsStr) -> bool {
+    let name = name.as_encoded_bytes();
+    name.starts_with(b".")
+        || name.starts_with(b"#")
+        || name.ends_with(b"~")
+        || name.ends_with(b".swp")
+        || name.ends_with(b".tmp")
+        || name.ends_with(b".bak")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+    use std::io::Cursor;
+
+    const CART: &str = "pico-8 cartridge // http://www.pico-8.com
+version 41
+__lua__
+-- main
+print(\"hi\")
+-->8
+-- util
+function f() end
+__gfx__
+00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000
+";
+
+    fn read(dir: &TempDir, name: &str) -> String {
+        std::fs::read_to_string(dir.path().join(name)).unwrap()
+    }
+
+    #[test]
+    fn dumps_from_a_reader() {
+        let dir = TempDir::new().unwrap();
+        let dumper = P8Dumper::from_reader(
+            Cursor::new(CART),
+            dir.path().to_path_buf(),
+            DumpOptions::default(),
+        );
+        let mut heard = Vec::new();
+        let results = dumper.dump(|w| heard.push(w.to_string())).unwrap();
+
+        assert_eq!(results.tab_order, ["main", "util"]);
+        assert_eq!(results.rsc_order, ["gfx"]);
+        assert!(results.warnings.is_empty());
+        assert!(heard.is_empty());
+        assert_eq!(read(&dir, "main.lua"), "-- main\nprint(\"hi\")\n");
+        assert_eq!(read(&dir, "util.lua"), "-- util\nfunction f() end\n");
+        assert_eq!(read(&dir, P8_VERSION_FILE), "41");
+        assert_eq!(read(&dir, TAB_ORDER_FILE), "main\nutil\n");
+    }
+
+    #[test]
+    fn warnings_reach_the_callback_and_the_results() {
+        let dir = TempDir::new().unwrap();
+        let cart = CART.replace("version 41\n", "");
+        let dumper = P8Dumper::from_reader(
+            Cursor::new(cart),
+            dir.path().to_path_buf(),
+            DumpOptions::default(),
+        );
+        let mut heard = Vec::new();
+        let results = dumper.dump(|w| heard.push(w.to_string())).unwrap();
+
+        assert_eq!(heard.len(), 1);
+        assert_eq!(results.warnings.len(), 1);
+        assert!(matches!(results.warnings[0], DumpWarning::NoVersion(_)));
+        assert!(!dir.path().join(P8_VERSION_FILE).exists());
+    }
+
+    #[test]
+    fn a_version_that_isnt_a_number_clears_the_old_version_file() {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.path().join(P8_VERSION_FILE), "38").unwrap();
+        let cart = CART.replace("version 41", "version beta");
+        let dumper = P8Dumper::from_reader(
+            Cursor::new(cart),
+            dir.path().to_path_buf(),
+            DumpOptions::default(),
+        );
+        let results = dumper.dump(|_| ()).unwrap();
+
+        assert_eq!(
+            results.warnings,
+            [DumpWarning::BadVersion {
+                line: "version beta".to_string(),
+                fallback: DEFAULT_P8_VERSION.to_string(),
+            }]
+        );
+        assert!(!dir.path().join(P8_VERSION_FILE).exists());
+        assert_eq!(read(&dir, "util.lua"), "-- util\nfunction f() end\n");
+    }
+
+    #[test]
+    fn block_comment_names_name_the_file() {
+        let dir = TempDir::new().unwrap();
+        let cart = CART.replace("-- util\n", "--[[ util ]]\n");
+        let dumper = P8Dumper::from_reader(
+            Cursor::new(cart),
+            dir.path().to_path_buf(),
+            DumpOptions::default(),
+        );
+        let results = dumper.dump(|_| ()).unwrap();
+
+        assert_eq!(results.tab_order, ["main", "util"]);
+        assert!(results.warnings.is_empty());
+        // The comment stays just the way it was, so the build puts it back.
+        assert_eq!(read(&dir, "util.lua"), "--[[ util ]]\nfunction f() end\n");
+    }
+
+    #[test]
+    fn kinds_that_only_differ_by_case_get_a_warning() {
+        let dir = TempDir::new().unwrap();
+        let cart = format!("{CART}__GFX__\n11\n");
+        let dumper = P8Dumper::from_reader(
+            Cursor::new(cart),
+            dir.path().to_path_buf(),
+            DumpOptions::default(),
+        );
+        let results = dumper.dump(|_| ()).unwrap();
+
+        assert_eq!(results.rsc_order, ["gfx", "GFX"]);
+        assert_eq!(
+            results.warnings,
+            [DumpWarning::CaseCollision {
+                first: "gfx".to_string(),
+                second: "GFX".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn a_short_last_row_looks_truncated() {
+        let dir = TempDir::new().unwrap();
+        let cart = format!("{CART}00000000\n");
+        let dumper = P8Dumper::from_reader(
+            Cursor::new(cart),
+            dir.path().to_path_buf(),
+            DumpOptions::default(),
+        );
+        let results = dumper.dump(|_| ()).unwrap();
+
+        assert_eq!(
+            results.warnings,
+            [DumpWarning::Truncated {
+                kind: "gfx".to_string(),
+                row: 8,
+                width: 128,
+            }]
+        );
+        // The data that's there still gets written.
+        assert!(read(&dir, "gfx.p8rsc").ends_with("\n00000000\n"));
+    }
+
+    #[test]
+    fn blank_lines_above_the_name_stay_in_the_file() {
+        let dir = TempDir::new().unwrap();
+        let cart = CART.replace("-- util\n", "\n\n-- util\n");
+        let dumper = P8Dumper::from_reader(
+            Cursor::new(cart),
+            dir.path().to_path_buf(),
+            DumpOptions::default(),
+        );
+        let results = dumper.dump(|_| ()).unwrap();
+
+        assert_eq!(results.tab_order, ["main", "util"]);
+        assert_eq!(read(&dir, "util.lua"), "\n\n-- util\nfunction f() end\n");
+    }
+
+    #[test]
+    fn only_if_changed_leaves_unchanged_files_alone() {
+        let dir = TempDir::new().unwrap();
+        let options = DumpOptions {
+            only_if_changed: true,
+            ..DumpOptions::default()
+        };
+        let dump = |cart: &str| {
+            P8Dumper::from_reader(
+                Cursor::new(cart.to_string()),
+                dir.path().to_path_buf(),
+                options.clone(),
+            )
+            .dump(|_| ())
+            .unwrap()
+        };
+        assert_eq!(dump(CART).written.len(), 6);
+
+        let main = dir.path().join("main.lua");
+        let long_ago = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1 << 30);
+        File::options()
+            .write(true)
+            .open(&main)
+            .unwrap()
+            .set_modified(long_ago)
+            .unwrap();
+        let results = dump(&CART.replace("function f() end", "function g() end"));
+        let written: Vec<_> = results
+            .written
+            .iter()
+            .map(|(path, _)| path.clone())
+            .collect();
+        assert_eq!(written, [dir.path().join("util.lua")]);
+        assert_eq!(results.outputs.len(), 6);
+        assert_eq!(
+            std::fs::metadata(&main).unwrap().modified().unwrap(),
+            long_ago
+        );
+    }
+
+    #[test]
+    fn excluded_sections_are_listed_but_not_written() {
+        let dir = TempDir::new().unwrap();
+        let options = DumpOptions {
+            exclude_sections: vec!["gfx".to_string()],
+            ..DumpOptions::default()
+        };
+        let cart = format!("{CART}__sfx__\n00\n");
+        let dumper = P8Dumper::from_reader(Cursor::new(cart), dir.path().to_path_buf(), options);
+        let results = dumper.dump(|_| ()).unwrap();
+
+        assert_eq!(results.rsc_order, ["gfx", "sfx"]);
+        assert_eq!(read(&dir, RSC_ORDER_FILE), "gfx\nsfx\n");
+        assert!(!dir.path().join("gfx.p8rsc").exists());
+        assert_eq!(read(&dir, "sfx.p8rsc"), "00\n");
+    }
+
+    #[test]
+    fn results_list_quiet_warnings_and_written_files() {
+        let dir = TempDir::new().unwrap();
+        let cart = "pico-8 cartridge // http://www.pico-8.com\nversion 41\n__lua__\nx=1\n-->8\n-- main\n-->8\n-- main\ny=2\n";
+        let dumper = P8Dumper::from_reader(
+            Cursor::new(cart),
+            dir.path().to_path_buf(),
+            DumpOptions::default(),
+        );
+        let results = dumper.dump(|_| ()).unwrap();
+
+        assert_eq!(results.tab_order, ["unknown-00", "main", "main-again"]);
+        assert_eq!(
+            results.warnings,
+            [
+                DumpWarning::SynthesizedName {
+                    index: 0,
+                    name: "unknown-00".to_string(),
+                },
+                DumpWarning::NameCollision {
+                    name: "main".to_string(),
+                    renamed: "main-again".to_string(),
+                },
+            ]
+        );
+        assert!(results.warnings.iter().all(|w| !w.is_loud()));
+        for (path, size) in results.written.iter() {
+            assert_eq!(std::fs::metadata(path).unwrap().len(), *size, "{path:?}");
+        }
+        assert_eq!(results.written.len(), results.outputs.len());
+    }
+
+    #[test]
+    fn canonical_names_follow_the_contents() {
+        let options = DumpOptions {
+            canonical_names: true,
+            ..DumpOptions::default()
+        };
+        let names = |lua: &str| {
+            let dir = TempDir::new().unwrap();
+            let cart =
+                format!("pico-8 cartridge // http://www.pico-8.com\nversion 41\n__lua__\n{lua}");
+            P8Dumper::from_reader(Cursor::new(cart), dir.path().to_path_buf(), options.clone())
+                .dump(|_| ())
+                .unwrap()
+                .tab_order
+        };
+        let before = names("-- main\n-->8\nx=1\n");
+        let after = names("-- main\n-->8\ny=2\n-->8\nx=1\n");
+        assert_eq!(before[1], format!("tab-{:08x}", fnv1a(b"x=1\n")));
+        assert_eq!(after[2], before[1]);
+        assert_ne!(after[1], before[1]);
+    }
+}
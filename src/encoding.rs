@@ -0,0 +1,80 @@
+use serde::Deserialize;
+use std::borrow::Cow;
+
+/// How to treat the text in carts and component files. PICO-8 itself writes
+/// UTF-8, but not every editor (or every hand-made cart) agrees.
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// Everything is UTF-8, and anything else is an error.
+    #[default]
+    Utf8,
+    /// The cart is UTF-8, but the component files are Latin-1.
+    Latin1,
+    /// Don't decode anything; bytes go through exactly as they are.
+    Bytes,
+}
+
+impl Encoding {
+    /// How to read and write the .p8 file.
+    pub fn cart(self) -> Codec {
+        match self {
+            Self::Utf8 | Self::Latin1 => Codec::Utf8,
+            Self::Bytes => Codec::Latin1,
+        }
+    }
+
+    /// How to read and write the component and meta files.
+    pub fn files(self) -> Codec {
+        match self {
+            Self::Utf8 => Codec::Utf8,
+            Self::Latin1 | Self::Bytes => Codec::Latin1,
+        }
+    }
+}
+
+/// One way of turning bytes into text and back. Latin-1 maps every byte to
+/// the char with the same number, so it can carry arbitrary bytes through a
+/// String and back out unharmed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Utf8,
+    Latin1,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum EncodingError {
+    #[error("Not valid UTF-8 (try --encoding bytes?)")]
+    Utf8(#[from] std::string::FromUtf8Error),
+    #[error("The character '{0}' can't be written as Latin-1.")]
+    NotLatin1(char),
+}
+
+impl From<EncodingError> for std::io::Error {
+    fn from(e: EncodingError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+impl Codec {
+    pub fn decode(self, bytes: Vec<u8>) -> Result<String, EncodingError> {
+        match self {
+            Self::Utf8 => Ok(String::from_utf8(bytes)?),
+            Self::Latin1 => Ok(bytes.iter().map(|&b| b as char).collect()),
+        }
+    }
+
+    pub fn encode(self, text: &str) -> Result<Cow<'_, [u8]>, EncodingError> {
+        match self {
+            Self::Utf8 => Ok(Cow::Borrowed(text.as_bytes())),
+            // ASCII is the same either way, so skip the copy.
+            Self::Latin1 if text.is_ascii() => Ok(Cow::Borrowed(text.as_bytes())),
+            Self::Latin1 => text
+                .chars()
+                .map(|c| u8::try_from(c).map_err(|_| EncodingError::NotLatin1(c)))
+                .collect::<Result<Vec<u8>, _>>()
+                .map(Cow::Owned),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+// A tiny glob matcher for tab names, so we don't need a dependency for it.
+// `*` matches any run of characters (including none), `?` matches exactly one
+// character, and everything else matches itself.
+
+/// Does the whole name match the pattern?
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where to pick back up if the most recent `*` needs to eat one more char.
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, eaten)) => {
+                    p = star + 1;
+                    n = eaten + 1;
+                    backtrack = Some((star, eaten + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    // Only trailing stars left to match against nothing.
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stars_and_question_marks() {
+        assert!(matches("debug_*", "debug_"));
+        assert!(matches("debug_*", "debug_draw"));
+        assert!(!matches("debug_*", "main"));
+        assert!(matches("*_test", "player_test"));
+        assert!(matches("a*b*c", "abbbc"));
+        assert!(!matches("a*b*c", "abbb"));
+        assert!(matches("tab?", "tab1"));
+        assert!(!matches("tab?", "tab"));
+        assert!(!matches("tab?", "tab12"));
+        assert!(matches("*", ""));
+        assert!(matches("🐱*", "🐱 cat"));
+    }
+}
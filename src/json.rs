@@ -0,0 +1,159 @@
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+
+use picoeater::{version_line, Codec, Section, CART_HEADER};
+
+use crate::report::Report;
+use crate::{
+    is_version_number, resource_sort_key, validate_rsc, BuildError, BuildOptions, EolWriter,
+    LineWrite, LuaTweaks,
+};
+
+/// A whole cart as one JSON document, for tools that would rather not deal
+/// with a directory full of files. It's just the parsed sections, with every
+/// body kept verbatim.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct CartJson {
+    pub version: Option<String>,
+    pub header: String,
+    pub tabs: Vec<TabJson>,
+    pub resources: Vec<ResourceJson>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TabJson {
+    pub name: Option<String>,
+    pub body: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResourceJson {
+    pub kind: String,
+    pub body: String,
+}
+
+impl CartJson {
+    pub fn from_sections(sections: Vec<Section>) -> Self {
+        let mut cart = Self::default();
+        for section in sections {
+            match section {
+                Section::Header { version, body } => {
+                    cart.version = version;
+                    cart.header = body;
+                }
+                Section::LuaTab { name, body } => cart.tabs.push(TabJson { name, body }),
+                Section::Resource { kind, body } => {
+                    cart.resources.push(ResourceJson { kind, body })
+                }
+            }
+        }
+        cart
+    }
+}
+
+/// Write a .p8 straight from a sections JSON file. Like a normal build, the
+/// header gets normalized, and the build options that aren't about component
+/// files (the version, encoding, stamp, lua tweaks, validation, warnings, and
+/// --strict) work the same. Otherwise, the tabs and resources go in exactly
+/// as they are. (JSON is always UTF-8, but the cart gets the usual encoding.)
+pub fn build_from_json(
+    json: &Path,
+    cart: &Path,
+    options: &BuildOptions,
+    report: &mut Report,
+) -> anyhow::Result<()> {
+    let warnings_before = report.warnings.len();
+    let text = std::fs::read_to_string(json)?;
+    let mut parsed: CartJson = serde_json::from_str(&text)?;
+    // Check the resources before creating the cart truncates it.
+    if options.validate {
+        for rsc in parsed.resources.iter() {
+            validate_rsc(&rsc.kind, &rsc.body, json)?;
+        }
+    }
+    let file = std::fs::File::create(cart)?;
+    let mut writer = EolWriter::new(std::io::BufWriter::new(file), options.crlf)
+        .with_codec(options.encoding.cart());
+    writer.write_strline(CART_HEADER)?;
+    let version = match parsed.version.as_deref().map(str::trim) {
+        _ if options.no_version_file => options.fallback_version.as_str(),
+        Some(version) if is_version_number(version) => version,
+        Some(version) => {
+            report.warn(&format!(
+                "{} says version {}, which isn't a number; using the default version {} instead.",
+                json.to_string_lossy(),
+                version,
+                options.fallback_version
+            ));
+            options.fallback_version.as_str()
+        }
+        None => options.fallback_version.as_str(),
+    };
+    writer.write_strline(&version_line(version))?;
+    writer.write_strline("__lua__")?;
+    if let Some(max) = options.warn_tab_count {
+        if parsed.tabs.len() > max {
+            report.warn(&format!(
+                "The cart has {} tabs, more than the {} you asked for.",
+                parsed.tabs.len(),
+                max
+            ));
+        }
+    }
+    for (i, tab) in parsed.tabs.iter().enumerate() {
+        if i > 0 {
+            writer.write_strline("-->8")?;
+        }
+        let tweaks = LuaTweaks {
+            trim: options.trim_trailing_whitespace,
+            stamp: options.stamp.as_deref().filter(|_| i == 0),
+            name_written: false,
+            module: options.module_tabs,
+        };
+        let lines = tweaks.apply(&tab.body);
+        if let Some(max) = options.warn_tab_lines {
+            if lines.len() > max {
+                report.warn(&format!(
+                    "Tab '{}' is {} lines long, more than the {} you asked for.",
+                    tab.name.as_deref().unwrap_or("(unnamed)"),
+                    lines.len(),
+                    max
+                ));
+            }
+        }
+        for line in lines {
+            writer.write_strline(line)?;
+        }
+    }
+    if options.sort_resources {
+        parsed
+            .resources
+            .sort_by(|a, b| resource_sort_key(&a.kind).cmp(&resource_sort_key(&b.kind)));
+    }
+    for rsc in parsed.resources.iter() {
+        writer.write_strline(&format!("__{}__", rsc.kind))?;
+        for line in rsc.body.lines() {
+            writer.write_strline(line)?;
+        }
+    }
+    writer.flush()?;
+    let warning_count = report.warnings.len() - warnings_before;
+    if options.strict && warning_count > 0 {
+        return Err(BuildError::Strict(warning_count).into());
+    }
+    Ok(())
+}
+
+/// Parse a cart (decoding it with `codec`) and write it out as a sections
+/// JSON file.
+pub fn dump_to_json(cart: &Path, json: &Path, codec: Codec) -> anyhow::Result<()> {
+    let text = codec.decode(std::fs::read(cart)?)?;
+    let sections = picoeater::parse_sections(text.as_bytes())?;
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(json)?);
+    serde_json::to_writer_pretty(&mut writer, &CartJson::from_sections(sections))?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+    Ok(())
+}
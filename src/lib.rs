@@ -0,0 +1,26 @@
+//! The cart-wrangling guts of picoeater, for when you want to read a .p8
+//! file's structure without the CLI writing a pile of files for you.
+
+#[macro_use]
+mod log;
+mod cart;
+mod dump;
+mod encoding;
+mod parse;
+pub mod sections;
+mod tempdir;
+
+pub use cart::Cart;
+pub use dump::{
+    is_hidden_or_temp, tab_name_problem, DumpError, DumpOptions, DumpResults, DumpWarning,
+    P8Dumper, P8_VERSION_FILE, RSC_ORDER_FILE, STRIPPED_FILE, TAB_ORDER_FILE,
+};
+pub use encoding::{Codec, Encoding, EncodingError};
+pub use parse::{
+    include_tag, lua_tag, parse_sections, parse_sections_traced, parse_sections_with_spans,
+    rsc_tag, tab_name, version_line, ParseError, Section, Transition, CART_HEADER,
+    DEFAULT_P8_VERSION,
+};
+// Only here for the CLI's scratch dirs; not part of the library.
+#[doc(hidden)]
+pub use tempdir::TempDir;
@@ -0,0 +1,91 @@
+// Build and dump take turns on a component directory, so two of them started
+// at once can't clobber each other's files.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Where the lock for a component directory lives: a file in the temp dir,
+/// named after a hash of the directory's canonical path. (Keeping it out of
+/// the directory itself means there's nothing to clean up or `.gitignore`.)
+fn lock_path(dir: &Path) -> std::io::Result<PathBuf> {
+    let canonical = dir.canonicalize()?;
+    let hash = fnv1a(canonical.as_os_str().as_encoded_bytes());
+    Ok(std::env::temp_dir().join(format!("picoeater-{:08x}.lock", hash)))
+}
+
+/// 32-bit FNV-1a. Any picoeater has to come up with the same lock name for a
+/// directory, so this can't be the std hasher, which may change between Rust
+/// versions.
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, &b| {
+        (hash ^ u32::from(b)).wrapping_mul(0x01000193)
+    })
+}
+
+/// Take the lock on a component directory, calling `on_wait` first if some
+/// other build or dump has it and we have to wait. The lock lasts as long as
+/// the returned file stays open, and the OS lets go of it if we crash. A
+/// directory that doesn't exist yet has nothing to protect, so that's no lock.
+pub fn lock_dir(dir: &Path, on_wait: impl FnOnce()) -> std::io::Result<Option<File>> {
+    if !dir.is_dir() {
+        return Ok(None);
+    }
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path(dir)?)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(std::fs::TryLockError::WouldBlock) => {
+            on_wait();
+            file.lock()?;
+        }
+        Err(std::fs::TryLockError::Error(e)) => return Err(e),
+    }
+    Ok(Some(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn second_taker_waits_for_the_first() {
+        let scratch = TempDir::new().unwrap();
+        let dir = scratch.path().to_path_buf();
+        let first = lock_dir(&dir, || panic!("nobody else has it")).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let waiter = std::thread::spawn(move || {
+            let waited = std::cell::Cell::new(false);
+            let _second = lock_dir(&dir, || waited.set(true)).unwrap();
+            tx.send(waited.get()).unwrap();
+        });
+        // Still stuck behind the first lock...
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        drop(first);
+        // ...until it's let go.
+        assert!(rx.recv_timeout(Duration::from_secs(10)).unwrap());
+        waiter.join().unwrap();
+    }
+
+    #[test]
+    fn leaves_nothing_in_the_directory() {
+        let scratch = TempDir::new().unwrap();
+        let lock = lock_dir(scratch.path(), || ()).unwrap();
+        assert!(lock.is_some());
+        drop(lock);
+        assert_eq!(std::fs::read_dir(scratch.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn no_lock_for_a_missing_directory() {
+        let scratch = TempDir::new().unwrap();
+        let missing = scratch.path().join("nope");
+        assert!(lock_dir(&missing, || ()).unwrap().is_none());
+    }
+}
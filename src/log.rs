@@ -0,0 +1,57 @@
+// Thin wrappers around the `tracing` macros we use, so the rest of the code
+// doesn't have to sprinkle `cfg` attributes everywhere. With the `tracing`
+// feature off, these compile to nothing (but still type-check their arguments,
+// so nothing looks unused).
+//
+// Both the library and the CLI pull this file in, and neither one uses every
+// macro.
+#![allow(unused_macros)]
+
+#[cfg(feature = "tracing")]
+macro_rules! debug {
+    ($($arg:tt)+) => { tracing::debug!($($arg)+) };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        if false {
+            drop(format!($($arg)+));
+        }
+    };
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! trace {
+    ($($arg:tt)+) => { tracing::trace!($($arg)+) };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        if false {
+            drop(format!($($arg)+));
+        }
+    };
+}
+
+/// Enter a debug-level span for one section of a cart. Returns a guard; the
+/// span lasts until it's dropped.
+#[cfg(feature = "tracing")]
+macro_rules! section_span {
+    ($kind:expr, $name:expr) => {
+        tracing::debug_span!("section", kind = %$kind, name = %$name).entered()
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! section_span {
+    ($kind:expr, $name:expr) => {{
+        let _ = (&$kind, &$name);
+        $crate::log::NoSpan
+    }};
+}
+
+/// Stand-in for a span guard when tracing is off.
+#[cfg(not(feature = "tracing"))]
+pub struct NoSpan;
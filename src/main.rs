@@ -1,52 +1,47 @@
 use clap::{Parser, Subcommand};
+use picoeater::{
+    include_tag, is_hidden_or_temp, lua_tag, parse_sections, parse_sections_with_spans, rsc_tag,
+    sections, tab_name, tab_name_problem, version_line, Codec, DumpOptions, DumpResults,
+    DumpWarning, Encoding, P8Dumper, Section, TempDir, CART_HEADER, DEFAULT_P8_VERSION,
+    P8_VERSION_FILE, RSC_ORDER_FILE, STRIPPED_FILE, TAB_ORDER_FILE,
+};
+#[macro_use]
+mod log;
+mod config;
+#[cfg(test)]
+mod conformance;
+mod glob;
+mod json;
+mod lock;
+mod report;
+
+use report::Report;
 use std::{
-    collections::HashMap,
+    collections::BTreeMap,
     ffi::OsStr,
     fs::File,
-    io::{BufRead, BufReader, BufWriter, Write},
+    io::{BufRead, BufWriter, Write},
     path::{Path, PathBuf},
 };
 
-// Okay, so http://pico8wiki.com/index.php?title=P8FileFormat
-// - I'm gonna handle multiple lua files, and preserve the order
-//   they were found in the p8 file if applicable.
-// - I'm not gonna handle multiple gfx etc. sections. Don't do that.
-// - I'm gonna treat the sections non-exhaustively; not positive a new section
-//   hasn't been added since that write-up. But anything after the defined
-//   order goes randomly last.
-//
-// Here's the format:
-// pico-8 cartridge // http://www.pico-8.com
-// version 41
-// __lua__
-// -- dr chaos
-// ...
-// -->8
-// -- splash screen
-// ...
-// __gfx__
-// ...
-//
-// ...and eventually it ends.
-//
-// > The sections appear in the following order: a header, the Lua code (__lua__),
-// > the spritesheet (__gfx__), the sprite flags (__gff__), the cartridge label
-// > (__label__), the map (__map__), sound effects (__sfx__), and music patterns
-// > (__music__). These sections are described in more detail below.
-
 /// The order of known resources (other than lua!) in a .p8 file.
 const DEFAULT_RESOURCE_ORDER: [&str; 6] = ["gfx", "gff", "label", "map", "sfx", "music"];
-const DEFAULT_P8_VERSION: &str = "41";
-
-const RSC_ORDER_FILE: &str = "_rsc_order.p8meta";
-const TAB_ORDER_FILE: &str = "_tab_order.p8meta";
-const P8_VERSION_FILE: &str = "_version.p8meta";
 
 #[derive(Parser, Debug)]
 #[command(version)]
 struct Cli {
     #[command(subcommand)]
     commands: Commands,
+
+    /// Also save the warnings, problems, and a summary to this file when
+    /// we're done (as JSON, if it ends in `.json`).
+    #[arg(long, global = true, value_name = "PATH")]
+    report_file: Option<PathBuf>,
+
+    /// Log what's going on to stderr. Repeat for more detail (-vv).
+    #[cfg(feature = "tracing")]
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
 }
 
 #[derive(Subcommand, Debug)]
@@ -54,18 +49,223 @@ enum Commands {
     /// Build a .p8 file from a collection of individual component files.
     Build {
         /// The directory the component files should come from. Defaults to the
-        /// current working directory.
+        /// directory the .p8 file is in, if you name one, or else the current
+        /// working directory.
         #[arg(short, long)]
         dir: Option<PathBuf>,
 
         /// The combined .p8 file to build. If there's only one existing .p8 in the
         /// source directory, it defaults to replacing that.
         file: Option<PathBuf>,
+
+        /// Build straight into PICO-8's carts folder, as `<INSTALL>.p8`. Set
+        /// the PICO8_CARTS_DIR env var if your carts folder isn't in the usual spot.
+        #[arg(long, conflicts_with = "file")]
+        install: Option<String>,
+
+        /// Name the cart after a `-- @cart <name>` line in this tab, and
+        /// build it into the component directory. If the tab doesn't have
+        /// one, fall back to the filename argument or the usual default.
+        #[arg(long, value_name = "TAB", conflicts_with = "install")]
+        cart_name_from_tab: Option<String>,
+
+        /// Warn about component files that mix CRLF and LF line endings, which
+        /// can be a sign of a botched merge. (They get normalized either way.)
+        #[arg(long, overrides_with = "no_warn_mixed_eol")]
+        warn_mixed_eol: bool,
+
+        /// Turn --warn-mixed-eol back off, if picoeater.toml turns it on.
+        #[arg(long, overrides_with = "warn_mixed_eol")]
+        no_warn_mixed_eol: bool,
+
+        /// End every line of the built cart with CRLF instead of LF.
+        #[arg(long, overrides_with = "no_crlf")]
+        crlf: bool,
+
+        /// Turn --crlf back off, if picoeater.toml turns it on.
+        #[arg(long, overrides_with = "crlf")]
+        no_crlf: bool,
+
+        /// Warn if the cart has more than this many tabs (default 16, which
+        /// is all PICO-8's editor can show).
+        #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "16")]
+        warn_tab_count: Option<usize>,
+
+        /// Warn about any tab longer than this many lines.
+        #[arg(long, value_name = "N")]
+        warn_tab_lines: Option<usize>,
+
+        /// Fail the build if there were any warnings.
+        #[arg(long, overrides_with = "no_strict")]
+        strict: bool,
+
+        /// Turn --strict back off, if picoeater.toml turns it on.
+        #[arg(long, overrides_with = "strict")]
+        no_strict: bool,
+
+        /// Write section markers for resource files that are empty, instead
+        /// of leaving those sections out.
+        #[arg(long, overrides_with = "no_include_empty_sections")]
+        include_empty_sections: bool,
+
+        /// Turn --include-empty-sections back off, if picoeater.toml turns it on.
+        #[arg(long, overrides_with = "include_empty_sections")]
+        no_include_empty_sections: bool,
+
+        /// Only build tabs whose names match this glob (`*` and `?` work).
+        /// Can be given more than once.
+        #[arg(long, value_name = "GLOB")]
+        include_tabs: Vec<String>,
+
+        /// Leave out tabs whose names match this glob, like `debug_*`. Can be
+        /// given more than once, and wins over --include-tabs.
+        #[arg(long, value_name = "GLOB")]
+        exclude_tabs: Vec<String>,
+
+        /// Write resource sections in PICO-8's usual order (then any unknown
+        /// kinds alphabetically), ignoring _rsc_order.p8meta.
+        #[arg(long, overrides_with = "no_sort_resources")]
+        sort_resources: bool,
+
+        /// Turn --sort-resources back off, if picoeater.toml turns it on.
+        #[arg(long, overrides_with = "sort_resources")]
+        no_sort_resources: bool,
+
+        /// Check that every row of a known resource section is the right
+        /// width, and that there aren't too many rows, before writing it.
+        #[arg(long, overrides_with = "no_validate")]
+        validate: bool,
+
+        /// Turn --validate back off, if picoeater.toml turns it on.
+        #[arg(long, overrides_with = "validate")]
+        no_validate: bool,
+
+        /// After building, dump the new cart again and make sure it comes back
+        /// out the same as the component files that went in.
+        #[arg(long, overrides_with = "no_verify")]
+        verify: bool,
+
+        /// Turn --verify back off, if picoeater.toml turns it on.
+        #[arg(long, overrides_with = "verify")]
+        no_verify: bool,
+
+        /// Write a JSON source map to this file, saying which tab file and
+        /// line each line of the cart's lua came from.
+        #[arg(long, value_name = "PATH")]
+        source_map: Option<PathBuf>,
+
+        /// Read the component files from a zip archive (as made by
+        /// `dump --zip`) instead of a directory.
+        #[arg(long, value_name = "ZIP")]
+        from_zip: Option<PathBuf>,
+
+        /// How to read the component files: `utf8` (the default), `latin1`
+        /// for Latin-1 files going into a UTF-8 cart, or `bytes` to pass
+        /// everything through untouched.
+        #[arg(long, value_enum)]
+        encoding: Option<Encoding>,
+
+        /// Build the cart from a sections JSON file (as made by
+        /// `dump --sections-json`) instead of component files. The options
+        /// that only make sense for component files can't go with it.
+        #[arg(
+            long,
+            value_name = "JSON",
+            conflicts_with_all = [
+                "from_zip",
+                "cart_name_from_tab",
+                "warn_mixed_eol",
+                "include_empty_sections",
+                "include_tabs",
+                "exclude_tabs",
+                "verify",
+                "source_map",
+            ]
+        )]
+        from_json: Option<PathBuf>,
+
+        /// Strip trailing spaces and tabs off every line of lua on the way
+        /// into the cart. Resource sections are left alone.
+        #[arg(long, overrides_with = "no_trim_trailing_whitespace")]
+        trim_trailing_whitespace: bool,
+
+        /// Turn --trim-trailing-whitespace back off, if picoeater.toml turns it on.
+        #[arg(long, overrides_with = "trim_trailing_whitespace")]
+        no_trim_trailing_whitespace: bool,
+
+        /// Experimental: wrap each tab's code in a `do ... end` block, so
+        /// `local`s in one tab can't be seen from the others. This is one-way;
+        /// dumping the cart keeps the wrappers in the tab files.
+        #[arg(long, overrides_with = "no_module_tabs")]
+        module_tabs: bool,
+
+        /// Turn --module-tabs back off, if picoeater.toml turns it on.
+        #[arg(long, overrides_with = "module_tabs")]
+        no_module_tabs: bool,
+
+        /// Don't take the lock on the component directory. Another build or
+        /// dump working there at the same time could clobber this one.
+        #[arg(long)]
+        no_lock: bool,
+
+        /// Print a diff of the new cart against an existing one. With no
+        /// path, compares against whatever's at the target before the
+        /// build overwrites it.
+        #[arg(long, value_name = "CART", num_args = 0..=1)]
+        compare_with: Option<Option<PathBuf>>,
+
+        /// How many unchanged lines to show around each change in the
+        /// --compare-with diff.
+        #[arg(long, value_name = "N", default_value_t = 3, requires = "compare_with")]
+        diff_context: usize,
+
+        /// Show the whole cart in the --compare-with diff, not just the
+        /// changes and the lines around them.
+        #[arg(long, requires = "compare_with", conflicts_with = "diff_context")]
+        no_context: bool,
+
+        /// Run this shell command after a successful build, with the cart's
+        /// path in the PICOEATER_CART env var. If it fails, so does the build.
+        #[arg(long, value_name = "COMMAND")]
+        post_build: Option<String>,
+
+        /// Only warn if the --post-build command fails.
+        #[arg(long, overrides_with = "no_ignore_hook_failure")]
+        ignore_hook_failure: bool,
+
+        /// Turn --ignore-hook-failure back off, if picoeater.toml turns it on.
+        #[arg(long, overrides_with = "ignore_hook_failure")]
+        no_ignore_hook_failure: bool,
+
+        /// Put a `-- built <date> by picoeater v<version>` line at the top
+        /// of the first tab (under its name), replacing any earlier stamp.
+        #[arg(long, overrides_with = "no_stamp")]
+        stamp: bool,
+
+        /// Turn --stamp back off, if picoeater.toml turns it on.
+        #[arg(long, overrides_with = "stamp")]
+        no_stamp: bool,
+
+        /// Ignore any _version.p8meta and always write the default version,
+        /// for when the version is managed somewhere else.
+        #[arg(long, overrides_with = "version_file")]
+        no_version_file: bool,
+
+        /// Use _version.p8meta after all, if picoeater.toml says `no_version_file`.
+        #[arg(long, overrides_with = "no_version_file")]
+        version_file: bool,
+
+        /// The version to write when _version.p8meta doesn't say. Defaults
+        /// to the PICOEATER_DEFAULT_VERSION env var, then `assume_version` in
+        /// the config, then 41.
+        #[arg(long, value_name = "VERSION")]
+        assume_version: Option<String>,
     },
     /// Dump a collection of individual component files from a .p8 file.
     Dump {
         /// The directory the component files should go in. Defaults to the
-        /// current working directory.
+        /// directory the .p8 file is in, if you name one, or else the current
+        /// working directory.
         #[arg(short, long)]
         dir: Option<PathBuf>,
 
@@ -77,337 +277,1943 @@ enum Commands {
         /// the source .p8 file, delete them.
         #[arg(short, long)]
         purge: bool,
+
+        /// Instead of warning about component files in the target dir that
+        /// aren't in the source .p8 file, just print their paths, one per line.
+        #[arg(long, conflicts_with = "purge")]
+        list_extra: bool,
+
+        /// Don't insert a `-- unknown-NN` name comment into tabs that lack one.
+        /// The tab still gets an `unknown-NN.lua` filename, but the built cart
+        /// keeps it anonymous.
+        #[arg(long, overrides_with = "synth_names")]
+        no_synth_names: bool,
+
+        /// Give anonymous tabs a name comment after all, if picoeater.toml says `no_synth_names`.
+        #[arg(long, overrides_with = "no_synth_names")]
+        synth_names: bool,
+
+        /// Name anonymous tabs after a hash of their contents (`tab-1a2b3c4d`)
+        /// instead of their position (`unknown-NN`), so adding or moving a
+        /// tab doesn't rename the others.
+        #[arg(long, overrides_with = "no_canonical_names")]
+        canonical_names: bool,
+
+        /// Turn --canonical-names back off, if picoeater.toml turns it on.
+        #[arg(long, overrides_with = "canonical_names")]
+        no_canonical_names: bool,
+
+        /// Make sure every written file is all the way on disk (fsync) before
+        /// finishing. Slower, but safer if something else picks the files up
+        /// right away.
+        #[arg(long, overrides_with = "no_fsync")]
+        fsync: bool,
+
+        /// Turn --fsync back off, if picoeater.toml turns it on.
+        #[arg(long, overrides_with = "fsync")]
+        no_fsync: bool,
+
+        /// Leave each tab's `-- name` first line out of its .lua file; build
+        /// puts it back. (Tabs whose first line isn't exactly that keep it.)
+        #[arg(long, overrides_with = "no_strip_name_comment")]
+        strip_name_comment: bool,
+
+        /// Turn --strip-name-comment back off, if picoeater.toml turns it on.
+        #[arg(long, overrides_with = "strip_name_comment")]
+        no_strip_name_comment: bool,
+
+        /// Refuse to overwrite any existing file that didn't come from an
+        /// earlier dump (that is, isn't listed in the old order files).
+        #[arg(long, overrides_with = "clobber")]
+        no_clobber: bool,
+
+        /// Let the dump overwrite files after all, if picoeater.toml says `no_clobber`.
+        #[arg(long, overrides_with = "no_clobber")]
+        clobber: bool,
+
+        /// Fail if the destination directory doesn't exist yet, instead of
+        /// creating it (and any missing parents).
+        #[arg(long, overrides_with = "no_dest_must_exist")]
+        dest_must_exist: bool,
+
+        /// Turn --dest-must-exist back off, if picoeater.toml turns it on.
+        #[arg(long, overrides_with = "dest_must_exist")]
+        no_dest_must_exist: bool,
+
+        /// Don't take the lock on the destination directory. Another build
+        /// or dump working there at the same time could clobber this one.
+        #[arg(long)]
+        no_lock: bool,
+
+        /// Only write one file for tabs that are byte-for-byte identical; the
+        /// repeats point at it from _tab_order.p8meta.
+        #[arg(long, overrides_with = "no_dedupe_tabs")]
+        dedupe_tabs: bool,
+
+        /// Turn --dedupe-tabs back off, if picoeater.toml turns it on.
+        #[arg(long, overrides_with = "dedupe_tabs")]
+        no_dedupe_tabs: bool,
+
+        /// Fail (after writing everything) if there were any warnings, like
+        /// a cart that looks truncated.
+        #[arg(long, overrides_with = "no_strict")]
+        strict: bool,
+
+        /// Turn --strict back off, if picoeater.toml turns it on.
+        #[arg(long, overrides_with = "strict")]
+        no_strict: bool,
+
+        /// Don't rewrite files whose contents wouldn't change, so their
+        /// modification times stay put.
+        #[arg(long, overrides_with = "no_only_if_changed")]
+        only_if_changed: bool,
+
+        /// Turn --only-if-changed back off, if picoeater.toml turns it on.
+        #[arg(long, overrides_with = "only_if_changed")]
+        no_only_if_changed: bool,
+
+        /// Don't write _version.p8meta, for when the version is managed
+        /// somewhere else. (Build it with --no-version-file too.)
+        #[arg(long, overrides_with = "version_file")]
+        no_version_file: bool,
+
+        /// Use _version.p8meta after all, if picoeater.toml says `no_version_file`.
+        #[arg(long, overrides_with = "no_version_file")]
+        version_file: bool,
+
+        /// Print each step the parser takes through the cart to stderr
+        /// (line number, old state, new state), for working out why a
+        /// weird cart dumped the way it did.
+        #[arg(long)]
+        trace_sections: bool,
+
+        /// Write the component files into a single zip archive instead of
+        /// loose files in the directory.
+        #[arg(long, value_name = "ZIP", conflicts_with_all = ["purge", "list_extra"])]
+        zip: Option<PathBuf>,
+
+        /// Store these resource kinds (comma-separated, like `label,gfx`) as
+        /// raw binary `.p8bin` files instead of hex text.
+        #[arg(long, value_name = "KINDS", value_delimiter = ',')]
+        binary_sections: Vec<String>,
+
+        /// Skip writing these resource kinds (comma-separated, like
+        /// `gfx,map,sfx`). They stay in _rsc_order.p8meta, and any files
+        /// already there for them are left alone.
+        #[arg(long, value_name = "KINDS", value_delimiter = ',')]
+        exclude_sections: Vec<String>,
+
+        /// How to write the component files: `utf8` (the default), `latin1`
+        /// for Latin-1 files from a UTF-8 cart, or `bytes` to pass everything
+        /// through untouched.
+        #[arg(long, value_enum)]
+        encoding: Option<Encoding>,
+
+        /// Instead of component files, write the whole cart as one JSON
+        /// document: its version, header, tabs, and resources.
+        #[arg(long, value_name = "JSON", conflicts_with_all = ["purge", "list_extra", "zip"])]
+        sections_json: Option<PathBuf>,
+
+        /// Dump every cart matching this pattern (like `'carts/*.p8'`; `*`
+        /// and `?` work in the filename) into its own subdirectory, named
+        /// after the cart. The subdirectories go next to the carts, or in
+        /// --dir if you give one.
+        #[arg(long, value_name = "PATTERN", conflicts_with_all = ["file", "zip", "sections_json"])]
+        input_glob: Option<PathBuf>,
+
+        /// Also write a list of every file the dump produced (tabs,
+        /// resources, and meta files) to this path, one per line, or as a
+        /// JSON array if it ends in `.json`. Files left alone by
+        /// --only-if-changed still count.
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["zip", "sections_json"])]
+        manifest: Option<PathBuf>,
     },
-}
+    /// Add a new script tab to a dumped cart, reading its Lua from stdin.
+    AddTab {
+        /// The directory the component files live in. Defaults to the
+        /// current working directory.
+        #[arg(short, long)]
+        dir: Option<PathBuf>,
 
-fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+        /// The name of the new tab. Becomes both its filename and its
+        /// first-line comment.
+        #[arg(short, long)]
+        name: String,
 
-    match cli.commands {
-        Commands::Build { dir, file } => {
-            // sort out the dir
-            let cwd = std::env::current_dir()?;
-            let abs_dir = cwd.join(dir.unwrap_or_else(PathBuf::new));
-            let real_file = match file {
-                Some(f) => f,
-                None => get_default_p8(&abs_dir)?,
-            };
+        /// If a tab by that name already exists, overwrite it instead of bailing.
+        #[arg(short, long)]
+        replace: bool,
 
-            let builder = P8Builder::new(real_file, abs_dir)?;
-            builder.build()?;
-        }
-        Commands::Dump { dir, file, purge } => {
-            // sort out the dir
-            let cwd = std::env::current_dir()?;
-            let abs_dir = cwd.join(dir.unwrap_or_else(PathBuf::new));
-            let real_file = match file {
-                Some(f) => f,
-                None => get_default_p8(&abs_dir)?,
-            };
+        /// How the component files are encoded, same as for `dump` and
+        /// `build`. Defaults to the `encoding` in picoeater.toml, if any.
+        #[arg(long, value_enum)]
+        encoding: Option<Encoding>,
+    },
+    /// Tidy up a dumped cart's component files in place: normalize line
+    /// endings, make sure each tab starts with its name comment, and clean
+    /// blank lines out of the order files.
+    Format {
+        /// The directory the component files live in. Defaults to the
+        /// current working directory.
+        #[arg(short, long)]
+        dir: Option<PathBuf>,
 
-            let dumper = P8Dumper::new(real_file, abs_dir.clone())?;
-            let DumpResults {
-                tab_order,
-                rsc_order,
-            } = dumper.dump()?;
-            let mut components = ComponentFiles::list(abs_dir)?;
-            components.remove_script_names(&tab_order);
-            components.remove_resource_kinds(&rsc_order);
-            if !components.is_empty() {
-                if purge {
-                    println!("Purging extra component files not included in the source .p8:");
-                    for path in components.iter() {
-                        println!("  - {}", path.to_string_lossy());
-                        std::fs::remove_file(path)?;
-                    }
-                } else {
-                    println!("WARNING: The target directory contains extra component files that weren't included in the source .p8:\n");
-                    for path in components.iter() {
-                        println!("  - {}", path.to_string_lossy());
-                    }
-                    println!("\nFor a quick way to delete these extra files, run dump again with the `--purge` flag.")
-                }
-            }
-        }
-    }
+        /// Also strip trailing spaces and tabs off every line of lua.
+        #[arg(long)]
+        trim_trailing_whitespace: bool,
 
-    Ok(())
-}
+        /// Also tidy up hex resource files: lowercase the digits and drop
+        /// stray spaces. The values never change; rows that still aren't the
+        /// right width get a warning and are left alone.
+        #[arg(long)]
+        canonicalize_hex: bool,
 
-trait LineWrite {
-    fn write_line(&mut self, buf: &[u8]) -> std::io::Result<()>;
+        /// How the component files are encoded, same as for `dump` and
+        /// `build`. Defaults to the `encoding` in picoeater.toml, if any.
+        #[arg(long, value_enum)]
+        encoding: Option<Encoding>,
+    },
+    /// Look over a dumped cart's component files for common problems, and
+    /// suggest fixes. Doesn't change anything.
+    Doctor {
+        /// The directory the component files live in. Defaults to the
+        /// current working directory.
+        #[arg(short, long)]
+        dir: Option<PathBuf>,
 
-    fn write_strline(&mut self, line: &str) -> std::io::Result<()> {
-        self.write_line(line.as_ref())
-    }
-}
+        /// A .p8 file to compare the component files against. If there's
+        /// only one .p8 in the directory, it defaults to that.
+        file: Option<PathBuf>,
 
-impl<W> LineWrite for W
-where
-    W: std::io::Write,
-{
-    fn write_line(&mut self, buf: &[u8]) -> std::io::Result<()> {
-        self.write_all(buf)?;
-        self.write_all(b"\n")
-    }
-}
+        /// How the component files are encoded, same as for `dump` and
+        /// `build`. Defaults to the `encoding` in picoeater.toml, if any.
+        #[arg(long, value_enum)]
+        encoding: Option<Encoding>,
+    },
+    /// List every `#include` in the tabs, and whether the file it points to
+    /// exists. Doesn't expand anything.
+    Includes {
+        /// The directory the component files live in. Defaults to the
+        /// current working directory.
+        #[arg(short, long)]
+        dir: Option<PathBuf>,
 
-#[derive(thiserror::Error, Debug)]
-enum DefaultP8Error {
-    #[error("No default .p8: zero existing .p8 files in the working directory.\nYou'll need to specify a filename.")]
-    Zero,
-    #[error("No default .p8: too many existing .p8 files in the working directory.\nYou'll need to specify a filename.")]
-    TooMany,
-}
+        /// How the component files are encoded, same as for `dump` and
+        /// `build`. Defaults to the `encoding` in picoeater.toml, if any.
+        #[arg(long, value_enum)]
+        encoding: Option<Encoding>,
+    },
+    /// Print reference info about what picoeater knows.
+    Info {
+        #[command(subcommand)]
+        topic: InfoTopic,
+    },
+    /// Delete all the component and meta files a dump would write, leaving
+    /// just the .p8 (and anything else that isn't ours).
+    Clean {
+        /// The directory the component files live in. Defaults to the
+        /// current working directory.
+        #[arg(short, long)]
+        dir: Option<PathBuf>,
 
-fn get_default_p8(dir: impl AsRef<Path>) -> anyhow::Result<PathBuf> {
-    let p8ext = OsStr::new("p8");
-    let mut p8s: Vec<PathBuf> = std::fs::read_dir(dir)?
-        .filter_map(|entry| entry.ok().map(|e| e.path()))
-        .filter(|path| match path.extension() {
-            Some(ext) => ext == p8ext,
-            None => false,
-        })
-        .collect();
-    if p8s.is_empty() {
-        return Err(DefaultP8Error::Zero.into());
-    } else if p8s.len() > 1 {
-        return Err(DefaultP8Error::TooMany.into());
-    }
-    Ok(p8s.pop().unwrap())
+        /// Don't ask before deleting.
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Print one tab or resource section of a .p8 file to stdout, without
+    /// writing any files.
+    Cat {
+        /// The .p8 file to read. If there's only one .p8 in the current
+        /// directory, it defaults to that.
+        file: Option<PathBuf>,
+
+        /// The resource section to print, like `gfx`.
+        #[arg(
+            long,
+            value_name = "KIND",
+            conflicts_with = "tab",
+            required_unless_present = "tab"
+        )]
+        section: Option<String>,
+
+        /// The lua tab to print, by the name in its `-- name` comment.
+        #[arg(long, value_name = "NAME")]
+        tab: Option<String>,
+
+        /// Put a line number in front of each line, counting from 1 at the
+        /// start of the tab or section. For reading only.
+        #[arg(long)]
+        line_numbers: bool,
+
+        /// With --line-numbers, count from the start of the whole lua
+        /// section instead (`-->8` lines included), like the source map's
+        /// `lua_line` does.
+        #[arg(long, requires = "line_numbers", conflicts_with = "section")]
+        continuous: bool,
+
+        /// How the cart is encoded, same as for `dump`. Only `bytes` makes a
+        /// difference here. Defaults to the `encoding` in picoeater.toml, if
+        /// any.
+        #[arg(long, value_enum)]
+        encoding: Option<Encoding>,
+    },
+    /// List the sections of a .p8 file, in order.
+    Ls {
+        /// The .p8 file to read. If there's only one .p8 in the current
+        /// directory, it defaults to that.
+        file: Option<PathBuf>,
+
+        /// Also print where each section's body starts in the file, and how
+        /// many bytes long it is.
+        #[arg(long)]
+        offsets: bool,
+
+        /// Print the list as JSON.
+        #[arg(long)]
+        json: bool,
+
+        /// How the cart is encoded, same as for `dump`. Only `bytes` makes a
+        /// difference here. Defaults to the `encoding` in picoeater.toml, if
+        /// any.
+        #[arg(long, value_enum)]
+        encoding: Option<Encoding>,
+    },
+    /// Print the path build or dump would use, without doing anything.
+    /// Handy for wrapper scripts.
+    Resolve {
+        #[command(subcommand)]
+        target: ResolveTarget,
+    },
 }
 
-struct P8Dumper {
-    reader: BufReader<File>,
-    dest: PathBuf,
+#[derive(Subcommand, Debug)]
+enum ResolveTarget {
+    /// The default .p8 file: the only one in the directory. Fails if there
+    /// are zero or several.
+    Cart {
+        /// The directory to look in. Defaults to the current working directory.
+        #[arg(short, long)]
+        dir: Option<PathBuf>,
+    },
+    /// The component directory.
+    Dir {
+        /// Same as build and dump's --dir.
+        #[arg(short, long)]
+        dir: Option<PathBuf>,
+
+        /// A .p8 file, same as build and dump take; the directory defaults
+        /// to the one it's in.
+        file: Option<PathBuf>,
+    },
 }
 
-enum ReadState {
-    Init,
-    // LuaStart gets the script name on the next line, bc it goes "scissors \n comment".
-    LuaStart,
-    Lua { writer: BufWriter<File> },
-    // RscStart needs to remember the kind, because the separator is one line, not two.
-    RscStart { kind: String },
-    Rsc { writer: BufWriter<File> },
+#[derive(Subcommand, Debug)]
+enum InfoTopic {
+    /// The known resource section kinds, and their sizes.
+    Sections,
 }
 
-#[derive(thiserror::Error, Debug)]
-#[allow(clippy::enum_variant_names)]
-enum DumpError {
-    #[error("Somehow never got out of Init; either a bug or a corrupt .p8 file")]
-    EndInInit,
-    #[error("Somehow ended in LuaStart; either a bug or a corrupt .p8 file")]
-    EndInLuaStart,
-    #[error("Somehow ended in RscStart; either a bug or a corrupt .p8 file")]
-    EndInRscStart,
-}
-
-fn rsc_tag(line: &str) -> Option<&str> {
-    if line.len() > 4 && &line[0..2] == "__" && &line[(line.len() - 2)..line.len()] == "__" {
-        let rest = &line[2..(line.len() - 2)];
-        // I'm gonna do a real fast and loose one here so I don't have to take a regexp
-        // dep or hardcode the resource kinds.
-        // basically I want it to be one "word" in there.
-        if !rest.contains([' ', '=', '.']) {
-            return Some(rest);
-        }
-    }
-    None
-}
-
-// Note that this is only a valid question on the FIRST line of a lua
-// file, so only the LuaStart state can use it.
-fn lua_tag(line: &str) -> Option<&str> {
-    if line.len() > 2 && &line[0..2] == "--" {
-        let rest = line[2..].trim();
-        if rest.is_empty() {
-            None
-        } else {
-            Some(rest)
-        }
-    } else {
-        None
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    #[cfg(feature = "tracing")]
+    init_logging(cli.verbose);
+
+    let env = Env::from_process()?;
+    let mut report = Report::new(cli.commands.name());
+    let result = run(cli.commands, &env, &mut report);
+    if let Some(path) = cli.report_file {
+        report.finish(&result, &path)?;
     }
+    result
 }
 
-struct DumpResults {
-    tab_order: Vec<String>,
-    rsc_order: Vec<String>,
+/// The parts of the outside world that commands look at. They're gathered
+/// once up front, so tests can hand in their own instead of changing the
+/// whole process's.
+struct Env {
+    /// What relative paths are relative to.
+    cwd: PathBuf,
+    /// PICOEATER_DEFAULT_VERSION, if it's set.
+    default_version: Option<String>,
 }
 
-impl P8Dumper {
-    /// Make a new P8Reader from a provided absolute file path and dir path.
-    pub fn new(path: impl AsRef<Path>, dest: PathBuf) -> std::io::Result<Self> {
-        File::open(path).map(|file| Self {
-            reader: BufReader::new(file),
-            dest,
+impl Env {
+    fn from_process() -> std::io::Result<Self> {
+        Ok(Self {
+            cwd: std::env::current_dir()?,
+            default_version: std::env::var("PICOEATER_DEFAULT_VERSION").ok(),
         })
     }
+}
 
-    /// Do the dump. Returns the list of lua scripts written, and the list of resources written.
-    pub fn dump(self) -> anyhow::Result<DumpResults> {
-        // consume self
-        let Self { reader, dest } = self;
-        // initial state
-        let mut state = ReadState::Init;
-        // initial lua index
-        let mut lua_index = 0u8;
-        // Keep track of which files we wrote to in which order. We use this for builds,
-        // and for purging.
-        let mut tab_order: Vec<String> = Vec::new();
-        let mut rsc_order: Vec<String> = Vec::new();
-
-        // helper closure for resource writers, since we make those in two spots
-        let make_writer = |filename: &str| -> std::io::Result<BufWriter<File>> {
-            let path = dest.join(filename);
-            let file = File::create(path)?;
-            Ok(BufWriter::new(file))
-        };
+/// Install a stderr subscriber at a level based on how many `-v`s we got.
+#[cfg(feature = "tracing")]
+fn init_logging(verbosity: u8) {
+    let level = match verbosity {
+        0 => tracing::Level::WARN,
+        1 => tracing::Level::DEBUG,
+        _ => tracing::Level::TRACE,
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr)
+        .init();
+}
 
-        for item in reader.lines() {
-            let line = item?;
-            match &mut state {
-                ReadState::Init => {
-                    // Get version from the header, and wait for the lua section.
-                    if line.starts_with("version") {
-                        if let Some((_, ver)) = line.split_once(' ') {
-                            std::fs::write(P8_VERSION_FILE, ver)?;
-                        }
-                    }
+impl Commands {
+    /// The subcommand's name, as typed on the command line.
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Build { .. } => "build",
+            Self::Dump { .. } => "dump",
+            Self::AddTab { .. } => "add-tab",
+            Self::Format { .. } => "format",
+            Self::Doctor { .. } => "doctor",
+            Self::Includes { .. } => "includes",
+            Self::Info { .. } => "info",
+            Self::Clean { .. } => "clean",
+            Self::Cat { .. } => "cat",
+            Self::Ls { .. } => "ls",
+            Self::Resolve { .. } => "resolve",
+        }
+    }
+}
 
-                    if line == "__lua__" {
-                        state = ReadState::LuaStart;
-                    }
+fn run(commands: Commands, env: &Env, report: &mut Report) -> anyhow::Result<()> {
+    match commands {
+        Commands::Build {
+            dir,
+            file,
+            install,
+            cart_name_from_tab,
+            warn_mixed_eol,
+            no_warn_mixed_eol,
+            crlf,
+            no_crlf,
+            warn_tab_count,
+            warn_tab_lines,
+            strict,
+            no_strict,
+            include_empty_sections,
+            no_include_empty_sections,
+            include_tabs,
+            exclude_tabs,
+            sort_resources,
+            no_sort_resources,
+            validate,
+            no_validate,
+            verify,
+            no_verify,
+            source_map,
+            from_zip,
+            encoding,
+            from_json,
+            trim_trailing_whitespace,
+            no_trim_trailing_whitespace,
+            module_tabs,
+            no_module_tabs,
+            no_lock,
+            compare_with,
+            diff_context,
+            no_context,
+            post_build,
+            ignore_hook_failure,
+            no_ignore_hook_failure,
+            stamp,
+            no_stamp,
+            no_version_file,
+            version_file,
+            assume_version,
+        } => {
+            // sort out the dir
+            let cwd = &env.cwd;
+            let abs_dir = resolve_dir(cwd, dir, file.as_deref());
+            let config = config::load(&abs_dir)?.build;
+            // Hold this until the cart's written, so two builds here take turns.
+            let lock = if no_lock { None } else { take_lock(&abs_dir)? };
+            let named = match cart_name_from_tab {
+                Some(tab) => {
+                    let codec = encoding.or(config.encoding).unwrap_or_default().files();
+                    cart_name_from_tab_file(&abs_dir, &tab, codec, report)?
                 }
-                ReadState::LuaStart => {
-                    // Set up a new writer.
-                    // Do we have a script name from an initial comment?
-                    let maybe_name = lua_tag(&line);
-                    let mut name = match maybe_name {
-                        Some(tag) => tag.to_string(),
-                        None => format!("unknown-{:02}", lua_index),
-                    };
-                    // If there's a name collision, do something gross to avoid calamity.
-                    while tab_order.contains(&name) {
-                        name.push_str("-again");
-                    }
-                    let filename = format!("{}.lua", &name);
-                    let mut writer = make_writer(&filename)?;
-                    // If we didn't get a name from the initial line, guess what:
-                    // we'll damn well get one next time :] This makes THIS round-trip
-                    // inexact, but it should help keep subsequent round-trips more stable.
-                    if maybe_name.is_none() {
-                        writer.write_strline(&format!("-- {}", &name))?;
-                    }
-                    // Save the script name to tab order
-                    tab_order.push(name);
-                    // Write that initial line so we don't drop it!
-                    writer.write_strline(&line)?;
-                    // bump the index for next time
-                    lua_index += 1;
-                    // go.
-                    state = ReadState::Lua { writer };
+                None => None,
+            };
+            // A relative cart path is always from the cwd, --dir or no --dir.
+            let real_file = match (named, file, install) {
+                (Some(name), _, _) => abs_dir.join(p8_filename(&name)),
+                (None, Some(f), _) => cwd.join(f),
+                (None, None, Some(name)) => get_pico8_carts_dir()?.join(p8_filename(&name)),
+                (None, None, None) => get_build_target(&abs_dir, config.cart_name.clone())?,
+            };
+            debug!(
+                "building {} from {}",
+                real_file.display(),
+                abs_dir.display()
+            );
+
+            let mut options = BuildOptions {
+                warn_mixed_eol: switch(warn_mixed_eol, no_warn_mixed_eol, config.warn_mixed_eol),
+                crlf: switch(crlf, no_crlf, config.crlf),
+                warn_tab_count: warn_tab_count.or(config.warn_tab_count),
+                warn_tab_lines: warn_tab_lines.or(config.warn_tab_lines),
+                strict: switch(strict, no_strict, config.strict),
+                include_empty_sections: switch(
+                    include_empty_sections,
+                    no_include_empty_sections,
+                    config.include_empty_sections,
+                ),
+                include_tabs: if include_tabs.is_empty() {
+                    config.include_tabs
+                } else {
+                    include_tabs
+                },
+                exclude_tabs: if exclude_tabs.is_empty() {
+                    config.exclude_tabs
+                } else {
+                    exclude_tabs
+                },
+                sort_resources: switch(sort_resources, no_sort_resources, config.sort_resources),
+                validate: switch(validate, no_validate, config.validate),
+                encoding: encoding.or(config.encoding).unwrap_or_default(),
+                verify: switch(verify, no_verify, config.verify),
+                source_map: source_map.map(|path| cwd.join(path)),
+                trim_trailing_whitespace: switch(
+                    trim_trailing_whitespace,
+                    no_trim_trailing_whitespace,
+                    config.trim_trailing_whitespace,
+                ),
+                stamp: switch(stamp, no_stamp, config.stamp).then(build_stamp),
+                module_tabs: switch(module_tabs, no_module_tabs, config.module_tabs),
+                no_version_file: switch(no_version_file, version_file, config.no_version_file),
+                fallback_version: fallback_version(assume_version, env, config.assume_version)?,
+            };
+            // A pipe can't be read back, so anything that needs to look at
+            // the finished cart is off.
+            let to_pipe = is_fifo(&real_file);
+            if to_pipe && options.verify {
+                report.warn("Can't --verify a cart written to a pipe; skipping that.");
+                options.verify = false;
+            }
+            // Grab the old cart now, since the build's about to clobber it.
+            let compare = match compare_with {
+                Some(_) if to_pipe => {
+                    report.warn("Can't --compare-with a cart written to a pipe; skipping that.");
+                    None
                 }
-                ReadState::Lua { writer } => {
-                    if &line == "-->8" {
-                        // we're done!!
-                        writer.flush()?;
-                        // NEXT,
-                        state = ReadState::LuaStart;
-                    } else if let Some(rsc_kind) = rsc_tag(&line) {
-                        // we're done!
-                        writer.flush()?;
-                        // Next stop, resourceville
-                        state = ReadState::RscStart {
-                            kind: rsc_kind.to_string(),
-                        };
+                Some(path) => {
+                    let path = path.map_or_else(|| real_file.clone(), |path| cwd.join(path));
+                    let old = read_optional_file_as(&path, options.encoding.cart())?;
+                    Some((path, old))
+                }
+                None => None,
+            };
+            let codec = options.encoding.cart();
+            if let Some(json) = from_json {
+                json::build_from_json(&cwd.join(json), &real_file, &options, report)?;
+            } else {
+                // Zipped components get unpacked into a scratch dir first.
+                let scratch = match from_zip {
+                    Some(archive) => {
+                        let scratch = TempDir::new()?;
+                        unzip_into(&cwd.join(archive), scratch.path())?;
+                        Some(scratch)
+                    }
+                    None => None,
+                };
+                let source = match &scratch {
+                    Some(scratch) => scratch.path().to_path_buf(),
+                    None => abs_dir,
+                };
+                let builder = P8Builder::new(&real_file, source, options)?;
+                builder.build(report)?;
+            }
+            report.note(format!("Built {}", real_file.to_string_lossy()));
+            if let Some((path, old)) = compare {
+                let new = read_optional_file_as(&real_file, codec)?;
+                // (There's no "all of it" setting, but every line is plenty.)
+                let context = if no_context {
+                    old.lines().count().max(new.lines().count())
+                } else {
+                    diff_context
+                };
+                print_cart_diff(&path, &old, &new, context);
+            }
+            // (Let go before the hook, in case it runs picoeater here too.)
+            drop(lock);
+            if let Some(command) = post_build.or(config.post_build) {
+                let status = run_post_build(&command, &cwd.join(&real_file))?;
+                if !status.success() {
+                    if switch(
+                        ignore_hook_failure,
+                        no_ignore_hook_failure,
+                        config.ignore_hook_failure,
+                    ) {
+                        report.warn(&format!("The post-build command failed ({}).", status));
                     } else {
-                        // normal line. write!
-                        writer.write_strline(&line)?;
+                        return Err(BuildError::Hook(status).into());
                     }
                 }
-                ReadState::RscStart { kind } => {
-                    let kind = kind.clone();
-                    let filename = format!("{}.p8rsc", &kind);
-                    // also stash the kind to resource order
-                    rsc_order.push(kind);
-                    let mut writer = make_writer(&filename)?;
-                    // Write that initial line so we don't drop it!
-                    writer.write_strline(&line)?;
-                    // Handoff to Rsc state
-                    state = ReadState::Rsc { writer };
+            }
+        }
+        Commands::Dump {
+            dir,
+            file,
+            purge,
+            list_extra,
+            no_synth_names,
+            synth_names,
+            canonical_names,
+            no_canonical_names,
+            fsync,
+            no_fsync,
+            strip_name_comment,
+            no_strip_name_comment,
+            no_clobber,
+            clobber,
+            dest_must_exist,
+            no_dest_must_exist,
+            no_lock,
+            dedupe_tabs,
+            no_dedupe_tabs,
+            strict,
+            no_strict,
+            only_if_changed,
+            no_only_if_changed,
+            no_version_file,
+            version_file,
+            trace_sections,
+            zip,
+            binary_sections,
+            exclude_sections,
+            encoding,
+            sections_json,
+            input_glob,
+            manifest,
+        } => {
+            // Only count this dump's warnings, for --strict.
+            let warnings_before = report.warnings.len();
+            let extras = if purge {
+                Extras::Purge
+            } else if list_extra {
+                Extras::List
+            } else {
+                Extras::Warn
+            };
+            // sort out the dir
+            let cwd = &env.cwd;
+            let abs_dir = resolve_dir(cwd, dir.clone(), file.as_deref());
+            // With a glob, every match is the cart, so there's no default to
+            // find (and no complaining about too many).
+            let glob_carts = match &input_glob {
+                Some(pattern) => Some(glob_carts(&cwd.join(pattern))?),
+                None => None,
+            };
+            let real_file = match (file, &glob_carts) {
+                (Some(f), _) => cwd.join(f),
+                (None, Some(_)) => PathBuf::new(),
+                (None, None) => get_default_p8(&abs_dir)?,
+            };
+
+            let full_config = config::load(&abs_dir)?;
+            let config = full_config.dump;
+            let options = DumpOptions {
+                synth_names: !switch(no_synth_names, synth_names, config.no_synth_names),
+                canonical_names: switch(
+                    canonical_names,
+                    no_canonical_names,
+                    config.canonical_names,
+                ),
+                fsync: switch(fsync, no_fsync, config.fsync),
+                dedupe_tabs: switch(dedupe_tabs, no_dedupe_tabs, config.dedupe_tabs),
+                strip_name_comment: switch(
+                    strip_name_comment,
+                    no_strip_name_comment,
+                    config.strip_name_comment,
+                ),
+                binary_sections: if binary_sections.is_empty() {
+                    config.binary_sections
+                } else {
+                    binary_sections
+                },
+                exclude_sections: if exclude_sections.is_empty() {
+                    config.exclude_sections
+                } else {
+                    exclude_sections
+                },
+                encoding: encoding.or(config.encoding).unwrap_or_default(),
+                only_if_changed: switch(
+                    only_if_changed,
+                    no_only_if_changed,
+                    config.only_if_changed,
+                ),
+                no_version_file: switch(no_version_file, version_file, config.no_version_file),
+                trace_sections,
+                // (Not an error here; the build can complain if it's bad.)
+                fallback_version: fallback_version(None, env, full_config.build.assume_version)
+                    .unwrap_or_else(|_| DEFAULT_P8_VERSION.to_string()),
+            };
+            let strict = switch(strict, no_strict, config.strict);
+            let dest_must_exist =
+                switch(dest_must_exist, no_dest_must_exist, config.dest_must_exist);
+            let no_clobber = switch(no_clobber, clobber, config.no_clobber);
+            let mut produced = Vec::new();
+            if let Some((base, carts)) = glob_carts {
+                // Each cart gets a subdirectory named after it, next to the
+                // carts or under --dir.
+                let base = match dir {
+                    Some(dir) => cwd.join(dir),
+                    None => base,
+                };
+                if dest_must_exist && !base.is_dir() {
+                    return Err(DumpError::NoDest(base.to_string_lossy().to_string()).into());
                 }
-                ReadState::Rsc { writer } => {
-                    if let Some(rsc_kind) = rsc_tag(&line) {
-                        // we're done. next!
-                        writer.flush()?;
-                        state = ReadState::RscStart {
-                            kind: rsc_kind.to_string(),
-                        };
-                    } else {
-                        // normal line. write!
-                        writer.write_strline(&line)?;
+                for cart in carts.iter() {
+                    let stem = cart.file_stem().unwrap_or_default();
+                    let dest = base.join(stem);
+                    std::fs::create_dir_all(&dest)?;
+                    let _lock = if no_lock { None } else { take_lock(&dest)? };
+                    let DumpResults {
+                        tab_order,
+                        rsc_order,
+                        written,
+                        outputs,
+                        ..
+                    } = dump_into_dir(
+                        cart,
+                        cart,
+                        &dest,
+                        options.clone(),
+                        no_clobber,
+                        extras,
+                        report,
+                    )?;
+                    // (--list-extra's output is just paths, for piping.)
+                    if !list_extra {
+                        println!(
+                            "Dumped {} into {} ({} tab(s), {} resource(s); wrote {} file(s))",
+                            cart.to_string_lossy(),
+                            dest.to_string_lossy(),
+                            tab_order.len(),
+                            rsc_order.len(),
+                            written.len()
+                        );
                     }
+                    produced.extend(outputs);
+                }
+                if let Some(path) = manifest {
+                    write_manifest(&cwd.join(path), &produced)?;
                 }
+                let warning_count = report.warnings.len() - warnings_before;
+                if strict && warning_count > 0 {
+                    return Err(DumpError::Strict(warning_count).into());
+                }
+                return Ok(());
             }
-        }
-        // Do a final flush once we've consumed the whole file.
-        match state {
-            ReadState::Init => {
-                return Err(DumpError::EndInInit.into());
+            // Reading a pipe uses it up, and --no-clobber reads the cart
+            // twice, so copy a piped cart somewhere real first.
+            let piped = if is_fifo(&real_file) {
+                let scratch = TempDir::new()?;
+                let copy = scratch.path().join("piped.p8");
+                std::io::copy(&mut File::open(&real_file)?, &mut File::create(&copy)?)?;
+                Some((scratch, copy))
+            } else {
+                None
+            };
+            let cart = match &piped {
+                Some((_, copy)) => copy,
+                None => &real_file,
+            };
+            debug!("dumping {} into {}", real_file.display(), abs_dir.display());
+            if let Some(json) = sections_json {
+                let json = cwd.join(json);
+                json::dump_to_json(cart, &json, options.encoding.cart())?;
+                println!("Wrote sections to {}", json.to_string_lossy());
+                return Ok(());
             }
-            ReadState::LuaStart => {
-                return Err(DumpError::EndInLuaStart.into());
+            if let Some(archive) = zip {
+                // Dump into a scratch dir, then pack that up.
+                let scratch = TempDir::new()?;
+                let dumper = P8Dumper::new(cart, scratch.path().to_path_buf(), options)?;
+                dumper.dump(loud_warnings(report))?;
+                let archive = cwd.join(archive);
+                zip_dir(scratch.path(), &archive)?;
+                println!("Wrote component files to {}", archive.to_string_lossy());
+                let warning_count = report.warnings.len() - warnings_before;
+                if strict && warning_count > 0 {
+                    return Err(DumpError::Strict(warning_count).into());
+                }
+                return Ok(());
             }
-            ReadState::RscStart { .. } => {
-                return Err(DumpError::EndInRscStart.into());
+            // Sort out the destination now, instead of failing on every
+            // single file we try to write into it.
+            if !abs_dir.is_dir() {
+                if dest_must_exist {
+                    return Err(DumpError::NoDest(abs_dir.to_string_lossy().to_string()).into());
+                }
+                std::fs::create_dir_all(&abs_dir)?;
             }
-            ReadState::Lua { mut writer } => {
-                writer.flush()?;
+            let _lock = if no_lock { None } else { take_lock(&abs_dir)? };
+            let results = dump_into_dir(
+                cart, &real_file, &abs_dir, options, no_clobber, extras, report,
+            )?;
+            if let Some(path) = manifest {
+                produced.extend(results.outputs);
+                write_manifest(&cwd.join(path), &produced)?;
             }
-            ReadState::Rsc { mut writer } => {
-                writer.flush()?;
+            let warning_count = report.warnings.len() - warnings_before;
+            if strict && warning_count > 0 {
+                return Err(DumpError::Strict(warning_count).into());
             }
         }
-        // Write the tab order and resource order
-        let mut tab_writer = make_writer(TAB_ORDER_FILE)?;
-        for line in tab_order.iter() {
-            tab_writer.write_strline(line)?;
+        Commands::AddTab {
+            dir,
+            name,
+            replace,
+            encoding,
+        } => {
+            let cwd = &env.cwd;
+            let abs_dir = cwd.join(dir.unwrap_or_else(PathBuf::new));
+            let codec = project_encoding(encoding, &abs_dir)?.files();
+            add_tab(&abs_dir, &name, std::io::stdin().lock(), replace, codec)?;
         }
-        tab_writer.flush()?;
-        let mut rsc_writer = make_writer(RSC_ORDER_FILE)?;
-        for line in rsc_order.iter() {
-            rsc_writer.write_strline(line)?;
+        Commands::Format {
+            dir,
+            trim_trailing_whitespace,
+            canonicalize_hex,
+            encoding,
+        } => {
+            let cwd = &env.cwd;
+            let abs_dir = cwd.join(dir.unwrap_or_else(PathBuf::new));
+            let codec = project_encoding(encoding, &abs_dir)?.files();
+            let touched = format_dir(
+                &abs_dir,
+                trim_trailing_whitespace,
+                canonicalize_hex,
+                codec,
+                report,
+            )?;
+            if touched.is_empty() {
+                println!("Everything's already tidy.");
+            } else {
+                println!("Reformatted:");
+                for path in touched.iter() {
+                    println!("  - {}", path.to_string_lossy());
+                }
+            }
+        }
+        Commands::Doctor {
+            dir,
+            file,
+            encoding,
+        } => {
+            let cwd = &env.cwd;
+            let abs_dir = cwd.join(dir.unwrap_or_else(PathBuf::new));
+            let encoding = project_encoding(encoding, &abs_dir)?;
+            // The cart is optional here; if we can't find one, skip those checks.
+            let cart = file
+                .map(|f| cwd.join(f))
+                .or_else(|| get_default_p8(&abs_dir).ok());
+            let findings = diagnose_dir(&abs_dir, cart.as_deref(), encoding, env)?;
+            if findings.is_empty() {
+                println!("No problems found.");
+            } else {
+                println!("Found {} possible problem(s):\n", findings.len());
+                for finding in findings.iter() {
+                    println!("  - {}", finding.problem);
+                    println!("    Fix: {}", finding.fix);
+                }
+            }
+            report.findings = findings;
+        }
+        Commands::Includes { dir, encoding } => {
+            let cwd = &env.cwd;
+            let abs_dir = cwd.join(dir.unwrap_or_else(PathBuf::new));
+            let codec = project_encoding(encoding, &abs_dir)?.files();
+            let includes = find_includes(&abs_dir, codec)?;
+            if includes.is_empty() {
+                println!("No #includes found.");
+            }
+            for include in includes.iter() {
+                let status = if include.found { "ok" } else { "MISSING" };
+                println!(
+                    "{}:{}: #include {} ({})",
+                    include.tab, include.line, include.path, status
+                );
+            }
+            let missing = includes.iter().filter(|i| !i.found).count();
+            if missing > 0 {
+                report.warn(&format!(
+                    "{} included file(s) are missing, relative to {}.",
+                    missing,
+                    abs_dir.to_string_lossy()
+                ));
+            }
+        }
+        Commands::Info { topic } => match topic {
+            InfoTopic::Sections => {
+                println!("{:<8}{:>7}{:>7}  binary  tall", "kind", "width", "rows");
+                for info in sections::KNOWN_SECTIONS.iter() {
+                    println!(
+                        "{:<8}{:>7}{:>7}  {:<6}  {}",
+                        info.kind,
+                        info.width,
+                        info.rows,
+                        if info.hex { "yes" } else { "no" },
+                        info.tall_rows
+                            .map_or("-".to_string(), |rows| rows.to_string())
+                    );
+                }
+                println!("\nWidths are in characters. Rows are a maximum; PICO-8 leaves off");
+                println!("trailing empty rows. \"binary\" means --binary-sections can store it.");
+                println!("\"tall\" is the most rows in the bigger layout newer carts can use;");
+                println!("a section with more than the usual rows is using it.");
+            }
+        },
+        Commands::Cat {
+            file,
+            section,
+            tab,
+            line_numbers,
+            continuous,
+            encoding,
+        } => {
+            let cwd = &env.cwd;
+            let real_file = match file {
+                Some(f) => cwd.join(f),
+                None => get_default_p8(cwd)?,
+            };
+            let codec = project_encoding(encoding, real_file.parent().unwrap_or(cwd))?.cart();
+            let bytes = std::fs::read(&real_file)?;
+            let text = codec.decode(bytes)?;
+            // (For --continuous: where the tab starts in the lua section.
+            // Each earlier tab takes up its lines plus a `-->8`.)
+            let mut first_line = 1;
+            let body = parse_sections(text.as_bytes())?
+                .into_iter()
+                .find_map(|found| match (found, &section, &tab) {
+                    (Section::Resource { kind, body }, Some(want), _) if kind == *want => {
+                        Some(body)
+                    }
+                    (Section::LuaTab { name, body }, _, Some(want))
+                        if name.as_ref() == Some(want) =>
+                    {
+                        Some(body)
+                    }
+                    (Section::LuaTab { body, .. }, _, _) => {
+                        first_line += body.lines().count() + 1;
+                        None
+                    }
+                    _ => None,
+                });
+            match body {
+                Some(body) if line_numbers => {
+                    let start = if continuous { first_line } else { 1 };
+                    let last = start + body.lines().count().max(1) - 1;
+                    let width = last.to_string().len();
+                    let mut out = std::io::stdout().lock();
+                    for (number, line) in (start..).zip(body.lines()) {
+                        let numbered = format!("{:>width$}  {}", number, line, width = width);
+                        out.write_line(&codec.encode(&numbered)?)?;
+                    }
+                }
+                Some(body) => std::io::stdout().lock().write_all(&codec.encode(&body)?)?,
+                None => {
+                    return Err(match (section, tab) {
+                        (Some(kind), _) => CatError::NoSection(kind),
+                        (_, tab) => CatError::NoTab(tab.unwrap_or_default()),
+                    }
+                    .into())
+                }
+            }
+        }
+        Commands::Ls {
+            file,
+            offsets,
+            json,
+            encoding,
+        } => {
+            let cwd = &env.cwd;
+            let real_file = match file {
+                Some(f) => cwd.join(f),
+                None => get_default_p8(cwd)?,
+            };
+            let codec = project_encoding(encoding, real_file.parent().unwrap_or(cwd))?.cart();
+            let text = codec.decode(std::fs::read(&real_file)?)?;
+            let entries = list_sections(&text, codec, offsets)?;
+            if json {
+                serde_json::to_writer_pretty(std::io::stdout().lock(), &entries)?;
+                println!();
+            } else {
+                for entry in entries {
+                    match (entry.offset, entry.length) {
+                        (Some(offset), Some(length)) => {
+                            println!("{}\t{}\t{}", entry.section, offset, length)
+                        }
+                        _ => println!("{}", entry.section),
+                    }
+                }
+            }
+        }
+        Commands::Resolve { target } => {
+            let cwd = &env.cwd;
+            let path = match target {
+                ResolveTarget::Cart { dir } => get_default_p8(resolve_dir(cwd, dir, None))?,
+                ResolveTarget::Dir { dir, file } => resolve_dir(cwd, dir, file.as_deref()),
+            };
+            println!("{}", path.to_string_lossy());
+        }
+        Commands::Clean { dir, yes } => {
+            let cwd = &env.cwd;
+            let abs_dir = cwd.join(dir.unwrap_or_else(PathBuf::new));
+            let targets = clean_targets(&abs_dir)?;
+            if targets.is_empty() {
+                println!("Nothing to clean.");
+                return Ok(());
+            }
+            if !yes {
+                println!("About to delete:");
+                for path in targets.iter() {
+                    println!("  - {}", path.to_string_lossy());
+                }
+                print!("\nDelete these {} file(s)? [y/N] ", targets.len());
+                std::io::stdout().flush()?;
+                let mut answer = String::new();
+                std::io::stdin().read_line(&mut answer)?;
+                if !matches!(answer.trim(), "y" | "Y" | "yes") {
+                    println!("Okay, leaving them alone.");
+                    return Ok(());
+                }
+            }
+            println!("Removed:");
+            for path in targets.iter() {
+                std::fs::remove_file(path)?;
+                println!("  - {}", path.to_string_lossy());
+            }
         }
-        rsc_writer.flush()?;
-        Ok(DumpResults {
-            tab_order,
-            rsc_order,
-        })
-    }
-}
-
-#[derive(Debug)]
-struct P8Builder {
-    writer: BufWriter<File>,
-    source: PathBuf,
-}
-
-/// Takes a mutable reference to a writer and a source filename, and
-/// copies the source to the writer line-by-line, inserting "\n" newlines
-/// after each line. This is way less efficient than std::io::copy(), but
-/// it takes care of normalizing any missing final newlines, AND sorting
-/// out any rogue CRLFs.
-fn slurp_file_by_line<W, P>(writer: &mut W, path: P) -> std::io::Result<()>
-where
-    W: Write,
-    P: AsRef<Path>,
-{
-    let reader = BufReader::new(File::open(path)?);
-    for item in reader.lines() {
-        let line = item?;
-        writer.write_strline(&line)?;
+    }
+
+    Ok(())
+}
+
+/// One line of `ls`. Tabs show up as `lua:name` (or just `lua`, if they
+/// don't have a name).
+#[derive(serde::Serialize, Debug)]
+struct ListedSection {
+    section: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length: Option<usize>,
+}
+
+/// The lines of `ls`, for a cart that's been decoded with `codec`. Offsets
+/// are bytes into the file, not the decoded text.
+fn list_sections(text: &str, codec: Codec, offsets: bool) -> anyhow::Result<Vec<ListedSection>> {
+    // Spans are into the decoded text. Latin-1 is one byte per char in the
+    // file, but non-ASCII chars take two in the String.
+    let file_offset = |at: usize| match codec {
+        Codec::Utf8 => at,
+        Codec::Latin1 => text[..at].chars().count(),
+    };
+    Ok(parse_sections_with_spans(text.as_bytes())?
+        .into_iter()
+        .map(|(section, span)| ListedSection {
+            section: match section {
+                Section::Header { .. } => "header".to_string(),
+                Section::LuaTab {
+                    name: Some(name), ..
+                } => format!("lua:{}", name),
+                Section::LuaTab { name: None, .. } => "lua".to_string(),
+                Section::Resource { kind, .. } => kind,
+            },
+            offset: offsets.then(|| file_offset(span.start)),
+            length: offsets.then(|| file_offset(span.end) - file_offset(span.start)),
+        })
+        .collect())
+}
+
+#[derive(thiserror::Error, Debug)]
+enum CatError {
+    #[error("The cart doesn't have a __{0}__ section.")]
+    NoSection(String),
+    #[error("The cart doesn't have a tab named '{0}'.")]
+    NoTab(String),
+}
+
+/// Work out the component directory: --dir if we got one, or else next to
+/// the .p8 file if we got one of those, or else the cwd.
+fn resolve_dir(cwd: &Path, dir: Option<PathBuf>, file: Option<&Path>) -> PathBuf {
+    match (dir, file) {
+        // (Rebuilding it from its components drops any `.` bits, so
+        // `--dir .` doesn't leave `/./` in every path we print.)
+        (Some(dir), _) => cwd.join(dir).components().collect(),
+        (None, Some(file)) => match cwd.join(file).parent() {
+            Some(parent) => parent.to_path_buf(),
+            None => cwd.to_path_buf(),
+        },
+        (None, None) => cwd.to_path_buf(),
+    }
+}
+
+trait LineWrite {
+    fn write_line(&mut self, buf: &[u8]) -> std::io::Result<()>;
+
+    fn write_strline(&mut self, line: &str) -> std::io::Result<()> {
+        self.write_line(line.as_ref())
+    }
+}
+
+impl<W> LineWrite for W
+where
+    W: std::io::Write,
+{
+    fn write_line(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.write_all(buf)?;
+        self.write_all(LF)
+    }
+}
+
+const LF: &[u8] = b"\n";
+const CRLF: &[u8] = b"\r\n";
+
+/// A line writer whose line ending is decided at runtime, for the output
+/// cart. (Everything else we write always uses LF.)
+#[derive(Debug)]
+struct EolWriter<W> {
+    inner: W,
+    eol: &'static [u8],
+    codec: Codec,
+}
+
+impl<W: Write> EolWriter<W> {
+    fn new(inner: W, crlf: bool) -> Self {
+        let eol = if crlf { CRLF } else { LF };
+        Self {
+            inner,
+            eol,
+            codec: Codec::Utf8,
+        }
+    }
+
+    /// Encode text lines with something other than UTF-8.
+    fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> LineWrite for EolWriter<W> {
+    fn write_line(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.inner.write_all(buf)?;
+        self.inner.write_all(self.eol)
+    }
+
+    fn write_strline(&mut self, line: &str) -> std::io::Result<()> {
+        let codec = self.codec;
+        self.write_line(&codec.encode(line)?)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+enum DefaultP8Error {
+    #[error("No default .p8: zero existing .p8 files in the working directory.\nYou'll need to specify a filename.")]
+    Zero,
+    #[error("No default .p8: too many existing .p8 files in the working directory.\nYou'll need to specify a filename.")]
+    TooMany,
+}
+
+fn get_default_p8(dir: impl AsRef<Path>) -> anyhow::Result<PathBuf> {
+    let p8ext = OsStr::new("p8");
+    let mut p8s: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| match path.extension() {
+            Some(ext) => ext == p8ext,
+            None => false,
+        })
+        // A directory that happens to be named like a cart isn't one.
+        .filter(|path| path.is_file())
+        .collect();
+    if p8s.is_empty() {
+        return Err(DefaultP8Error::Zero.into());
+    } else if p8s.len() > 1 {
+        return Err(DefaultP8Error::TooMany.into());
+    }
+    Ok(p8s.pop().unwrap())
+}
+
+/// The default cart for a build. Same as `get_default_p8`, except that if
+/// there's no cart yet, it makes up a name for a new one: the config's
+/// `cart_name`, or else the directory's own name.
+fn get_build_target(dir: &Path, cart_name: Option<String>) -> anyhow::Result<PathBuf> {
+    match get_default_p8(dir) {
+        Err(e) if matches!(e.downcast_ref(), Some(DefaultP8Error::Zero)) => {
+            let Some(name) = cart_name.or_else(|| {
+                dir.file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+            }) else {
+                return Err(e);
+            };
+            let cart = dir.join(p8_filename(&name));
+            println!(
+                "No .p8 here yet; building a new one at {}",
+                cart.to_string_lossy()
+            );
+            Ok(cart)
+        }
+        found => found,
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+enum CartsDirError {
+    #[error("Couldn't figure out where PICO-8 keeps its carts on this OS.\nSet the PICO8_CARTS_DIR environment variable to point at your carts folder.")]
+    Unknown,
+    #[error("PICO-8 carts folder doesn't exist: {0}\nRun PICO-8 once to create it, or set the PICO8_CARTS_DIR environment variable.")]
+    Missing(PathBuf),
+}
+
+/// Find PICO-8's carts folder: either the PICO8_CARTS_DIR env var, or the
+/// standard per-OS location.
+fn get_pico8_carts_dir() -> anyhow::Result<PathBuf> {
+    let dir = match std::env::var_os("PICO8_CARTS_DIR") {
+        Some(d) => PathBuf::from(d),
+        None => default_pico8_carts_dir().ok_or(CartsDirError::Unknown)?,
+    };
+    if !dir.is_dir() {
+        return Err(CartsDirError::Missing(dir).into());
+    }
+    Ok(dir)
+}
+
+fn default_pico8_carts_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        std::env::var_os("APPDATA").map(|d| PathBuf::from(d).join("pico-8").join("carts"))
+    } else if cfg!(target_os = "macos") {
+        std::env::var_os("HOME")
+            .map(|d| PathBuf::from(d).join("Library/Application Support/pico-8/carts"))
+    } else {
+        std::env::var_os("HOME").map(|d| PathBuf::from(d).join(".lexaloffle/pico-8/carts"))
+    }
+}
+
+/// Turn a cart name into a .p8 filename, unless it already is one.
+fn p8_filename(name: &str) -> PathBuf {
+    let path = PathBuf::from(name);
+    if path.extension() == Some(OsStr::new("p8")) {
+        path
+    } else {
+        PathBuf::from(format!("{}.p8", name))
+    }
+}
+
+/// For --cart-name-from-tab: the name from a `-- @cart <name>` line in a tab,
+/// if it has one (and it's a usable filename).
+fn cart_name_from_tab_file(
+    dir: &Path,
+    tab: &str,
+    codec: Codec,
+    report: &mut Report,
+) -> anyhow::Result<Option<String>> {
+    let text = read_optional_file_as(dir.join(format!("{}.lua", tab)), codec)?;
+    let Some(name) = text.lines().find_map(|line| {
+        let rest = line.trim().strip_prefix("--")?.trim_start();
+        let name = rest.strip_prefix("@cart")?;
+        (name.is_empty() || name.starts_with(char::is_whitespace)).then(|| name.trim())
+    }) else {
+        debug!("no @cart line in {}", tab);
+        return Ok(None);
+    };
+    // Same rules as a tab name, since it's going to be a filename too.
+    if let Some(problem) = tab_name_problem(name).or(name.is_empty().then_some("is empty")) {
+        report.warn(&format!(
+            "Tab {} says the cart is named '{}', which {}; ignoring it.",
+            tab, name, problem
+        ));
+        return Ok(None);
+    }
+    Ok(Some(name.to_string()))
+}
+
+#[derive(thiserror::Error, Debug)]
+enum DumpError {
+    #[error("--no-clobber is on, and the dump would overwrite these files that didn't come from an earlier dump:\n{0}")]
+    Clobber(String),
+    #[error("The dump had {0} warning(s), and --strict is on.")]
+    Strict(usize),
+    #[error("Only the filename part of an --input-glob can have wildcards in it: {0}")]
+    GlobDir(String),
+    #[error("No .p8 files match {0}")]
+    NoGlobMatches(String),
+    #[error("The destination directory {0} doesn't exist, and --dest-must-exist is on.")]
+    NoDest(String),
+}
+
+/// Print a dump's warnings as they come up, or at least the ones worth
+/// printing. (The rest only go in the report, once the dump's done.)
+fn loud_warnings(report: &mut Report) -> impl FnMut(&DumpWarning) + '_ {
+    |warning| {
+        if warning.is_loud() {
+            report.warn(&warning.to_string());
+        }
+    }
+}
+
+/// For dump --manifest: list the files a dump produced, one per line, or as
+/// a JSON array for a `.json` path.
+fn write_manifest(path: &Path, files: &[PathBuf]) -> anyhow::Result<()> {
+    let text = if path.extension().is_some_and(|ext| ext == "json") {
+        let names: Vec<String> = files
+            .iter()
+            .map(|file| file.to_string_lossy().into_owned())
+            .collect();
+        let mut json = serde_json::to_string_pretty(&names)?;
+        json.push('\n');
+        json
+    } else {
+        files
+            .iter()
+            .map(|file| format!("{}\n", file.to_string_lossy()))
+            .collect()
+    };
+    std::fs::write(path, text)?;
+    Ok(())
+}
+
+/// What to do about component files a dump didn't write.
+#[derive(Debug, Clone, Copy)]
+enum Extras {
+    Warn,
+    Purge,
+    List,
+}
+
+/// For dump --input-glob: the directory the pattern points into, plus every
+/// file in it whose name matches, in filename order.
+fn glob_carts(pattern: &Path) -> Result<(PathBuf, Vec<PathBuf>), DumpError> {
+    let shown = || pattern.to_string_lossy().to_string();
+    let base = pattern.parent().unwrap_or(Path::new("")).to_path_buf();
+    if base.to_string_lossy().contains(['*', '?']) {
+        return Err(DumpError::GlobDir(shown()));
+    }
+    let name_pattern = pattern
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut carts: Vec<PathBuf> = std::fs::read_dir(&base)
+        .map_err(|_| DumpError::NoGlobMatches(shown()))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| glob::matches(&name_pattern, &entry.file_name().to_string_lossy()))
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect();
+    if carts.is_empty() {
+        return Err(DumpError::NoGlobMatches(shown()));
+    }
+    carts.sort();
+    Ok((base, carts))
+}
+
+/// Dump one cart into loose files in a directory, then deal with any extra
+/// files that were already there. `shown` is the cart's name for messages,
+/// in case we're actually reading a copy of it.
+fn dump_into_dir(
+    cart: &Path,
+    shown: &Path,
+    abs_dir: &Path,
+    options: DumpOptions,
+    no_clobber: bool,
+    extras: Extras,
+    report: &mut Report,
+) -> anyhow::Result<DumpResults> {
+    if no_clobber {
+        let conflicts = clobber_conflicts(cart, abs_dir, &options)?;
+        if !conflicts.is_empty() {
+            let list: Vec<String> = conflicts
+                .iter()
+                .map(|path| format!("  - {}", path.to_string_lossy()))
+                .collect();
+            return Err(DumpError::Clobber(list.join("\n")).into());
+        }
+    }
+    let dumper = P8Dumper::new(cart, abs_dir.to_path_buf(), options)?;
+    let results = dumper.dump(loud_warnings(report))?;
+    report.note(format!(
+        "Dumped {} into {} ({} tab(s), {} resource(s); wrote {} file(s), {} bytes)",
+        shown.to_string_lossy(),
+        abs_dir.to_string_lossy(),
+        results.tab_order.len(),
+        results.rsc_order.len(),
+        results.written.len(),
+        results.written.iter().map(|(_, size)| size).sum::<u64>()
+    ));
+    // The quiet ones didn't get printed, but they're still worth a line in
+    // the report.
+    for warning in results.warnings.iter().filter(|w| !w.is_loud()) {
+        report.note(warning.to_string());
+    }
+    let mut components = ComponentFiles::list(abs_dir)?;
+    components.remove_script_names(&results.tab_order);
+    components.remove_resource_kinds(&results.rsc_order);
+    match extras {
+        Extras::List => {
+            for path in components.iter() {
+                println!("{}", path.to_string_lossy());
+            }
+        }
+        _ if components.is_empty() => (),
+        Extras::Purge => {
+            println!("Purging extra component files not included in the source .p8:");
+            for path in components.iter() {
+                println!("  - {}", path.to_string_lossy());
+                std::fs::remove_file(path)?;
+            }
+        }
+        Extras::Warn => {
+            eprintln!("WARNING: The target directory contains extra component files that weren't included in the source .p8:\n");
+            for path in components.iter() {
+                eprintln!("  - {}", path.to_string_lossy());
+                report.add_warning(format!(
+                    "Extra component file not in the source .p8: {}",
+                    path.to_string_lossy()
+                ));
+            }
+            eprintln!("\nFor a quick way to delete these extra files, run dump again with the `--purge` flag.")
+        }
+    }
+    Ok(results)
+}
+
+/// For --no-clobber: work out which files a dump would write over (or delete)
+/// that aren't ours. A file counts as ours if it's a meta file, or the old order files
+/// list it (since then a previous dump wrote it). Does a practice dump into a
+/// scratch dir to get the exact filenames.
+fn clobber_conflicts(
+    cart: &Path,
+    dest: &Path,
+    options: &DumpOptions,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let scratch = TempDir::new()?;
+    let practice = DumpOptions {
+        // Once through is plenty.
+        trace_sections: false,
+        ..options.clone()
+    };
+    let results = P8Dumper::new(cart, scratch.path().to_path_buf(), practice)?.dump(|_| ())?;
+
+    let mut ours: Vec<String> = [
+        TAB_ORDER_FILE,
+        RSC_ORDER_FILE,
+        P8_VERSION_FILE,
+        STRIPPED_FILE,
+    ]
+    .iter()
+    .map(|meta| meta.to_string())
+    .collect();
+    let tab_order = read_optional_file_as(dest.join(TAB_ORDER_FILE), options.encoding.files())?;
+    for line in tab_order.lines() {
+        ours.push(format!("{}.lua", tab_order_entry(line).0));
+    }
+    let rsc_order = read_optional_file_as(dest.join(RSC_ORDER_FILE), options.encoding.files())?;
+    for kind in rsc_order.lines() {
+        ours.push(format!("{}.p8rsc", kind));
+        ours.push(format!("{}.p8bin", kind));
+    }
+
+    // Deleting a file is as good as writing over it.
+    let touched = results.outputs.iter().chain(results.cleared.iter());
+    let mut conflicts = Vec::new();
+    for name in touched.filter_map(|path| path.file_name()) {
+        let target = dest.join(name);
+        if target.exists()
+            && !ours.iter().any(|own| osstr_eq_bytes(name, own.as_bytes()))
+            && !conflicts.contains(&target)
+        {
+            conflicts.push(target);
+        }
+    }
+    conflicts.sort();
+    Ok(conflicts)
+}
+
+#[derive(Debug)]
+struct P8Builder {
+    writer: EolWriter<BufWriter<File>>,
+    cart: PathBuf,
+    source: PathBuf,
+    options: BuildOptions,
+}
+
+/// Knobs for how a build behaves.
+#[derive(Debug)]
+struct BuildOptions {
+    /// Whether to complain about component files with inconsistent line endings.
+    warn_mixed_eol: bool,
+    /// Whether the output cart uses CRLF line endings.
+    crlf: bool,
+    /// Complain if there are more tabs than this.
+    warn_tab_count: Option<usize>,
+    /// Complain about tabs with more lines than this.
+    warn_tab_lines: Option<usize>,
+    /// Whether any warnings should fail the build.
+    strict: bool,
+    /// Whether to emit a section for a resource file with nothing in it.
+    include_empty_sections: bool,
+    /// If there are any, only tabs matching one of these globs go in.
+    include_tabs: Vec<String>,
+    /// Tabs matching any of these globs stay out, no matter what.
+    exclude_tabs: Vec<String>,
+    /// Whether to use the canonical resource order instead of the order file.
+    sort_resources: bool,
+    /// Whether to check resource rows against their known sizes.
+    validate: bool,
+    /// How to read the files and write the cart.
+    encoding: Encoding,
+    /// Whether to re-dump the cart afterwards and check it against the source.
+    verify: bool,
+    /// Where to write a source map, if anywhere.
+    source_map: Option<PathBuf>,
+    /// Whether to strip trailing whitespace from lua lines.
+    trim_trailing_whitespace: bool,
+    /// A build stamp line for the top of the first tab, if we're stamping.
+    stamp: Option<String>,
+    /// Whether to wrap each tab in a `do ... end` block.
+    module_tabs: bool,
+    /// Whether to skip _version.p8meta and use the default version.
+    no_version_file: bool,
+    /// The version to write when _version.p8meta doesn't say.
+    fallback_version: String,
+}
+
+#[derive(thiserror::Error, Debug)]
+enum BuildError {
+    #[error("The build had {0} warning(s), and --strict is on.")]
+    Strict(usize),
+    #[error("Don't know how to turn {0}.p8bin back into hex; only fixed-width hex sections can be stored as binary.")]
+    UnknownBinaryKind(String),
+    #[error("{path}, line {line}: expected a row {expected} characters wide, but it's {actual}.")]
+    RowWidth {
+        path: String,
+        line: usize,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("{path}: a {kind} section can have at most {expected} rows, but this has {actual}.")]
+    RowCount {
+        path: String,
+        kind: String,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("The post-build command failed ({0}).")]
+    Hook(std::process::ExitStatus),
+    #[error("The fallback version has to be a plain number, like 41, not '{0}'.")]
+    FallbackVersion(String),
+}
+
+/// The version to use when a dumped directory doesn't have one:
+/// --assume-version, or else PICOEATER_DEFAULT_VERSION, or else the config,
+/// or else the built-in default.
+fn fallback_version(
+    flag: Option<String>,
+    env: &Env,
+    config: Option<String>,
+) -> Result<String, BuildError> {
+    let version = flag
+        .or_else(|| env.default_version.clone())
+        .or(config)
+        .map(|version| version.trim().to_string())
+        .unwrap_or_else(|| DEFAULT_P8_VERSION.to_string());
+    if is_version_number(&version) {
+        Ok(version)
+    } else {
+        Err(BuildError::FallbackVersion(version))
+    }
+}
+
+/// Run a post-build command through the shell, with the cart's path in
+/// PICOEATER_CART. Its output goes straight through to ours.
+fn run_post_build(command: &str, cart: &Path) -> std::io::Result<std::process::ExitStatus> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = std::process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = std::process::Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command).env("PICOEATER_CART", cart).status()
+}
+
+/// Stuff we noticed about a file while slurping it.
+struct SlurpInfo {
+    /// The file has both CRLF and bare LF line endings.
+    mixed_eol: bool,
+    /// How many lines we copied.
+    lines: usize,
+    /// The first line (number and text) that looks like a section tag, like
+    /// `__gfx__` or `__lua__`. Copied into the cart, it would start a new
+    /// section the next time anything reads it.
+    tag_line: Option<(usize, String)>,
+    /// The first line that's a `-->8`, which would split a tab in two.
+    scissors_line: Option<usize>,
+}
+
+/// Split a line of the tab order into the tab's name and the name of the file
+/// it's read from. Those are the same, except for tabs that `--dedupe-tabs`
+/// pointed at an identical tab's file (`name<TAB>original`).
+fn tab_order_entry(line: &str) -> (&str, &str) {
+    line.split_once('\t').unwrap_or((line, line))
+}
+
+/// Where each tab's lines ended up in the built cart, so anything that
+/// complains about a line of the cart's code can point back at the file it
+/// came from.
+#[derive(serde::Serialize, Debug, Default)]
+struct SourceMap {
+    tabs: Vec<TabSpan>,
+}
+
+/// One tab's run of lines in the built cart.
+#[derive(serde::Serialize, Debug)]
+struct TabSpan {
+    tab: String,
+    file: PathBuf,
+    /// 1-based line in the .p8 file where the tab starts.
+    cart_line: usize,
+    /// 1-based line within the lua section (the line after `__lua__` is 1,
+    /// and the `-->8` separators count).
+    lua_line: usize,
+    /// How many lines the tab is.
+    lines: usize,
+    /// Whether the first line is a name comment that build added (because
+    /// dump stripped it), so the file's line 1 is the tab's line 2.
+    name_comment: bool,
+}
+
+/// If dump stripped this tab file's name comment, returns the name to put back.
+fn stripped_name<'a>(path: &Path, stripped: &[&'a str]) -> Option<&'a str> {
+    let stem = path.file_stem()?;
+    stripped
+        .iter()
+        .find(|name| osstr_eq_bytes(stem, name.as_bytes()))
+        .copied()
+}
+
+/// Takes a mutable reference to a writer and a source filename, and
+/// copies the source to the writer line-by-line, inserting the writer's
+/// newlines after each line. This is way less efficient than std::io::copy(), but
+/// it takes care of normalizing any missing final newlines, AND sorting
+/// out any rogue CRLFs. It can also make some lua-only tweaks on the way.
+fn slurp_file_by_line<W, P>(
+    writer: &mut W,
+    path: P,
+    codec: Codec,
+    tweaks: LuaTweaks,
+) -> std::io::Result<SlurpInfo>
+where
+    W: LineWrite,
+    P: AsRef<Path>,
+{
+    // Read the raw bytes up front, so we can see the line endings before
+    // lines() strips them.
+    let bytes = std::fs::read(path)?;
+    let mut info = SlurpInfo {
+        mixed_eol: has_mixed_eol(&bytes),
+        lines: 0,
+        tag_line: None,
+        scissors_line: None,
+    };
+    let text = codec.decode(bytes)?;
+    for (number, line) in (1..).zip(text.lines()) {
+        if info.tag_line.is_none() && (line == "__lua__" || rsc_tag(line).is_some()) {
+            info.tag_line = Some((number, line.to_string()));
+        }
+        if info.scissors_line.is_none() && line == "-->8" {
+            info.scissors_line = Some(number);
+        }
+    }
+    for line in tweaks.apply(&text) {
+        writer.write_strline(line)?;
+        info.lines += 1;
+    }
+    Ok(info)
+}
+
+/// Take the trailing spaces and tabs off a line of lua, if asked.
+fn trim_line(line: &str, trim: bool) -> &str {
+    if trim {
+        line.trim_end_matches([' ', '\t'])
+    } else {
+        line
+    }
+}
+
+/// Changes build can make to a lua file's lines as it copies them. (Resource
+/// files get the default, which is no changes.)
+#[derive(Clone, Copy, Default)]
+struct LuaTweaks<'a> {
+    /// Strip trailing whitespace.
+    trim: bool,
+    /// Put this build stamp under the name comment, replacing any old one.
+    stamp: Option<&'a str>,
+    /// Build already wrote this tab's name comment, so the stamp goes first.
+    name_written: bool,
+    /// Wrap the tab's code in a `do ... end` block, so its locals stay put.
+    module: bool,
+}
+
+impl<'a> LuaTweaks<'a> {
+    fn apply<'b>(&self, text: &'b str) -> Vec<&'b str>
+    where
+        'a: 'b,
+    {
+        let mut lines: Vec<&str> = text
+            .lines()
+            .map(|line| trim_line(line, self.trim))
+            .collect();
+        if let Some(stamp) = self.stamp {
+            lines.retain(|line| !is_stamp(line));
+            // Keep the name comment (and any blank lines before it) on top,
+            // or the tab would get renamed.
+            let at = match lines.iter().position(|line| !line.trim().is_empty()) {
+                Some(i) if !self.name_written && lua_tag(lines[i]).is_some() => i + 1,
+                _ => 0,
+            };
+            lines.insert(at, stamp);
+        }
+        if self.module {
+            // Same deal: the name comment (and the stamp) stay outside the
+            // block, so the tab keeps its name.
+            let mut at = match lines.iter().position(|line| !line.trim().is_empty()) {
+                Some(i) if !self.name_written && lua_tag(lines[i]).is_some() => i + 1,
+                _ => 0,
+            };
+            if lines.get(at).is_some_and(|line| is_stamp(line)) {
+                at += 1;
+            }
+            lines.insert(at, "do");
+            lines.push("end");
+        }
+        lines
+    }
+}
+
+/// The line `--stamp` adds: `-- built 2026-10-16 by picoeater v0.1.3`.
+fn build_stamp() -> String {
+    let days = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() / 86400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "-- built {:04}-{:02}-{:02} by picoeater v{}",
+        year,
+        month,
+        day,
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// Whether a line is a build stamp from some earlier `--stamp` build.
+fn is_stamp(line: &str) -> bool {
+    line.starts_with("-- built ") && line.contains(" by picoeater v")
+}
+
+/// Days since 1970-01-01 to a (year, month, day) in UTC. This is Howard
+/// Hinnant's `civil_from_days`; not worth a whole date crate for one line.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// True if a file has nothing but whitespace in it.
+fn is_blank_file(path: impl AsRef<Path>) -> std::io::Result<bool> {
+    let bytes = std::fs::read(path)?;
+    Ok(bytes.iter().all(u8::is_ascii_whitespace))
+}
+
+fn has_mixed_eol(bytes: &[u8]) -> bool {
+    let mut crlf = false;
+    let mut lf = false;
+    for (i, b) in bytes.iter().enumerate() {
+        if *b == b'\n' {
+            if i > 0 && bytes[i - 1] == b'\r' {
+                crlf = true;
+            } else {
+                lf = true;
+            }
+        }
+    }
+    crlf && lf
+}
+
+/// Where a resource kind goes in the canonical order: known kinds in
+/// PICO-8's order, with any banks (`sfx0`, `sfx1`...) right after their base
+/// kind in number order, then unknown kinds alphabetically.
+fn resource_sort_key(kind: &str) -> (usize, &str, Option<u32>) {
+    let (base, bank) = sections::split_bank(kind);
+    let index = DEFAULT_RESOURCE_ORDER
+        .iter()
+        .position(|known| *known == base)
+        .unwrap_or(DEFAULT_RESOURCE_ORDER.len());
+    (index, base, bank)
+}
+
+/// Check a text resource file's rows against its section's known size,
+/// reading it with the same codec the build uses. Kinds we don't know about
+/// always pass.
+fn validate_rsc_file(kind: &str, path: &Path, codec: Codec) -> anyhow::Result<()> {
+    let text = codec.decode(std::fs::read(path)?)?;
+    validate_rsc(kind, &text, path)
+}
+
+/// Check a resource section's rows against its known size. `shown` says
+/// where the rows came from, for the error.
+fn validate_rsc(kind: &str, text: &str, shown: &Path) -> anyhow::Result<()> {
+    let Some(info) = sections::lookup(kind) else {
+        return Ok(());
+    };
+    let mut lines: Vec<&str> = text.lines().collect();
+    // PICO-8 puts a blank line after some sections (the label, for one), and
+    // that's fine.
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    let mut rows = 0;
+    for (i, line) in lines.iter().enumerate() {
+        // (Count characters, since a Latin-1 byte can decode to two.)
+        let width = line.chars().count();
+        if width != info.width {
+            return Err(BuildError::RowWidth {
+                path: shown.to_string_lossy().into_owned(),
+                line: i + 1,
+                expected: info.width,
+                actual: width,
+            }
+            .into());
+        }
+        rows += 1;
+    }
+    // A map with more than the usual rows is a tall map, which has more room.
+    if rows > info.max_rows() {
+        return Err(BuildError::RowCount {
+            path: shown.to_string_lossy().into_owned(),
+            kind: kind.to_string(),
+            expected: info.max_rows(),
+            actual: rows,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Dump a freshly built cart into a scratch dir, and check that the same tabs
+/// and resources come back out, with the same contents (give or take line
+/// endings). Any drift becomes a warning.
+fn verify_build(
+    cart: &Path,
+    tabs: &[(String, PathBuf)],
+    resources: &[(String, PathBuf)],
+    stripped: &[&str],
+    encoding: Encoding,
+    tweaks: LuaTweaks,
+    report: &mut Report,
+) -> anyhow::Result<()> {
+    let scratch = TempDir::new()?;
+    let options = DumpOptions {
+        // Anonymous tabs should stay anonymous, or they'd never match.
+        synth_names: false,
+        canonical_names: false,
+        fsync: false,
+        dedupe_tabs: false,
+        strip_name_comment: false,
+        binary_sections: Vec::new(),
+        exclude_sections: Vec::new(),
+        encoding,
+        only_if_changed: false,
+        no_version_file: false,
+        trace_sections: false,
+        fallback_version: DEFAULT_P8_VERSION.to_string(),
+    };
+    let DumpResults {
+        tab_order,
+        rsc_order,
+        ..
+    } = P8Dumper::new(cart, scratch.path().to_path_buf(), options)?.dump(|_| ())?;
+    let drift_before = report.warnings.len();
+    let codec = encoding.files();
+
+    let expected_tabs: Vec<&str> = tabs.iter().map(|(name, _)| name.as_str()).collect();
+    if expected_tabs != tab_order {
+        report.warn(&format!(
+            "Verify: expected tabs {:?} from a re-dump, but got {:?}.",
+            expected_tabs, tab_order
+        ));
+    }
+    for (i, (name, path)) in tabs.iter().enumerate() {
+        let dumped = scratch.path().join(format!("{}.lua", name));
+        if !dumped.is_file() {
+            continue;
+        }
+        let mut before = codec.decode(std::fs::read(path)?)?;
+        if let Some(name) = stripped_name(path, stripped) {
+            before.insert_str(0, &format!("-- {}\n", name));
+        }
+        // Make the same tweaks the build did. (Only the first tab gets stamped.)
+        let tweaks = LuaTweaks {
+            stamp: tweaks.stamp.filter(|_| i == 0),
+            ..tweaks
+        };
+        let after = codec.decode(std::fs::read(&dumped)?)?;
+        if !tweaks.apply(&before).into_iter().eq(after.lines()) {
+            report.warn(&format!("Verify: tab '{}' changed in a re-dump.", name));
+        }
+    }
+
+    let expected_rsc: Vec<&str> = resources.iter().map(|(kind, _)| kind.as_str()).collect();
+    if expected_rsc != rsc_order {
+        report.warn(&format!(
+            "Verify: expected resources {:?} from a re-dump, but got {:?}.",
+            expected_rsc, rsc_order
+        ));
+    }
+    for (kind, path) in resources.iter() {
+        let dumped = scratch.path().join(format!("{}.p8rsc", kind));
+        if !dumped.is_file() {
+            continue;
+        }
+        let before = if path.extension().is_some_and(|ext| ext == "p8bin") {
+            match sections::lookup(kind) {
+                Some(info) => sections::bytes_to_hex(&std::fs::read(path)?, info.width),
+                None => continue,
+            }
+        } else {
+            codec.decode(std::fs::read(path)?)?
+        };
+        let after = codec.decode(std::fs::read(&dumped)?)?;
+        if !before.lines().eq(after.lines()) {
+            report.warn(&format!(
+                "Verify: resource '{}' changed in a re-dump.",
+                kind
+            ));
+        }
+    }
+
+    if report.warnings.len() == drift_before {
+        println!("Round-trip verified.");
+        report.note("Round-trip verified".to_string());
     }
     Ok(())
 }
@@ -415,92 +2221,797 @@ where
 impl P8Builder {
     /// Make a new builder struct, given absolute paths to a p8 file target
     /// and a source directory.
-    pub fn new(path: impl AsRef<Path>, source: PathBuf) -> std::io::Result<Self> {
-        File::create(path).map(|file| Self {
-            writer: BufWriter::new(file),
+    pub fn new(
+        path: impl AsRef<Path>,
+        source: PathBuf,
+        options: BuildOptions,
+    ) -> anyhow::Result<Self> {
+        // Check the resources before creating the cart truncates it, so a bad
+        // one leaves the old cart alone.
+        if options.validate {
+            for (kind, rsc) in ComponentFiles::list(&source)?.rsc.iter() {
+                if rsc.extension().is_none_or(|ext| ext != "p8bin") {
+                    validate_rsc_file(kind, rsc, options.encoding.files())?;
+                }
+            }
+        }
+        let file = File::create(path.as_ref())?;
+        Ok(Self {
+            writer: EolWriter::new(BufWriter::new(file), options.crlf)
+                .with_codec(options.encoding.cart()),
+            cart: path.as_ref().to_path_buf(),
             source,
+            options,
         })
     }
 
     /// Do the build. Returns nothing on success.
-    pub fn build(self) -> anyhow::Result<()> {
-        let Self { mut writer, source } = self;
+    pub fn build(self, report: &mut Report) -> anyhow::Result<()> {
+        let Self {
+            mut writer,
+            cart,
+            source,
+            options,
+        } = self;
+        // Only count this build's warnings, for --strict.
+        let warnings_before = report.warnings.len();
+        // Copy a component file into the cart, and complain about it if needed.
+        // (Only lua gets tweaked; every character of a resource counts.)
+        let slurp = |writer: &mut EolWriter<BufWriter<File>>,
+                     path: &Path,
+                     tweaks: LuaTweaks,
+                     report: &mut Report|
+         -> std::io::Result<SlurpInfo> {
+            let info = slurp_file_by_line(writer, path, options.encoding.files(), tweaks)?;
+            if options.warn_mixed_eol && info.mixed_eol {
+                report.warn(&format!(
+                    "{} has a mix of CRLF and LF line endings.",
+                    path.to_string_lossy()
+                ));
+            }
+            if let Some((number, line)) = &info.tag_line {
+                report.warn(&format!(
+                    "{}, line {}: '{}' looks like a section tag, so the cart won't dump back the same.",
+                    path.to_string_lossy(),
+                    number,
+                    line
+                ));
+            }
+            Ok(info)
+        };
         // get the stuff
         let mut components = ComponentFiles::list(&source)?;
         // load the meta files
-        let tab_order = read_optional_text_file(source.join(TAB_ORDER_FILE))?;
-        let mut rsc_order = read_optional_text_file(source.join(RSC_ORDER_FILE))?;
+        let codec = options.encoding.files();
+        let tab_order = read_optional_file_as(source.join(TAB_ORDER_FILE), codec)?;
+        let mut rsc_order = read_optional_file_as(source.join(RSC_ORDER_FILE), codec)?;
         // tbh this shouldn't ever happen, but anyway:
         if rsc_order.trim().is_empty() {
             rsc_order = DEFAULT_RESOURCE_ORDER.join("\n");
             rsc_order.push('\n');
         }
-        let mut version = read_optional_text_file(source.join(P8_VERSION_FILE))?;
-        if version.trim().is_empty() {
-            version = DEFAULT_P8_VERSION.to_string();
+        let stripped_list = read_optional_file_as(source.join(STRIPPED_FILE), codec)?;
+        let stripped: Vec<&str> = stripped_list.lines().collect();
+        let mut version = if options.no_version_file {
+            String::new()
+        } else {
+            read_optional_file_as(source.join(P8_VERSION_FILE), codec)?
+        };
+        if version.trim().is_empty() {
+            version = options.fallback_version.clone();
+        } else if !is_version_number(version.trim()) {
+            // Same rule as dump: a version that isn't a number doesn't get
+            // written into the cart.
+            report.warn(&format!(
+                "{} says version {}, which isn't a number; using the default version {} instead.",
+                P8_VERSION_FILE,
+                version.trim(),
+                options.fallback_version
+            ));
+            version = options.fallback_version.clone();
+        }
+        // write header
+        writer.write_strline(CART_HEADER)?;
+        writer.write_strline(&version_line(version.trim()))?;
+        // write luas
+        writer.write_strline("__lua__")?;
+        // ...btw, writing these requires some finesse, because 1. I can't
+        // guarantee there's a newline at the end of each file, and 2. I
+        // need to keep track of which file is last so we don't write an extra
+        // scissors line.
+        // Well, we'll just go line-by-line. less efficient, but safer.
+        let mut first = true;
+        let mut source_map = SourceMap::default();
+        // The header and the __lua__ tag take up the first three lines.
+        let lua_start = 3;
+        let mut lua_line = 1;
+        // First the known tab order, then leftover scripts by name
+        let mut tabs: Vec<(String, PathBuf)> = Vec::new();
+        // (Repeated tabs borrow another tab's file, so look those up before
+        // we start taking files out of the list.)
+        let all_lua = components.lua.clone();
+        for line in tab_order.lines() {
+            let (script_name, file_name) = tab_order_entry(line);
+            if script_name != file_name {
+                components.lua.remove(script_name);
+                if let Some(path) = all_lua.get(file_name) {
+                    tabs.push((script_name.to_string(), path.clone()));
+                }
+            } else if let Some(path) = components.lua.remove(script_name) {
+                tabs.push((script_name.to_string(), path));
+            }
+        }
+        tabs.extend(std::mem::take(&mut components.lua));
+        // Filter tabs by name, if asked. Excludes win.
+        if !options.include_tabs.is_empty() || !options.exclude_tabs.is_empty() {
+            tabs.retain(|(script_name, _)| {
+                let included = options.include_tabs.is_empty()
+                    || options
+                        .include_tabs
+                        .iter()
+                        .any(|g| glob::matches(g, script_name));
+                let excluded = options
+                    .exclude_tabs
+                    .iter()
+                    .any(|g| glob::matches(g, script_name));
+                if !included || excluded {
+                    println!("Leaving out tab '{}'.", script_name);
+                }
+                included && !excluded
+            });
+        }
+        // An empty tab would just be a pair of scissors lines with nothing
+        // between, which doesn't survive a round-trip. Leave it out.
+        let mut kept = Vec::with_capacity(tabs.len());
+        for (script_name, path) in tabs {
+            if stripped_name(&path, &stripped).is_none() && is_blank_file(&path)? {
+                report.warn(&format!(
+                    "Tab '{}' is empty, so it's not going in the cart.",
+                    script_name
+                ));
+            } else {
+                kept.push((script_name, path));
+            }
+        }
+        let tabs = kept;
+        if let Some(max) = options.warn_tab_count {
+            if tabs.len() > max {
+                report.warn(&format!(
+                    "The cart has {} tabs, more than the {} you asked for.",
+                    tabs.len(),
+                    max
+                ));
+            }
+        }
+        for (script_name, path) in tabs.iter() {
+            let _section = section_span!("lua", script_name);
+            debug!("writing tab from {}", path.display());
+            if !first {
+                // scissor line
+                writer.write_strline("-->8")?;
+                lua_line += 1;
+            }
+            // Put back a name comment that dump took out.
+            let name_comment = stripped_name(path, &stripped);
+            if let Some(name) = name_comment {
+                writer.write_strline(&format!("-- {}", name))?;
+            }
+            let tweaks = LuaTweaks {
+                trim: options.trim_trailing_whitespace,
+                stamp: options.stamp.as_deref().filter(|_| first),
+                name_written: name_comment.is_some(),
+                module: options.module_tabs,
+            };
+            first = false;
+            let info = slurp(&mut writer, path, tweaks, report)?;
+            // (In a resource, a `-->8` is just a line; in lua it ends the tab.)
+            if let Some(number) = info.scissors_line {
+                report.warn(&format!(
+                    "{}, line {}: a -->8 line splits this tab in two, so the cart won't dump back the same.",
+                    path.to_string_lossy(),
+                    number
+                ));
+            }
+            let lines = info.lines + usize::from(name_comment.is_some());
+            source_map.tabs.push(TabSpan {
+                tab: script_name.clone(),
+                file: path.clone(),
+                cart_line: lua_start + lua_line,
+                lua_line,
+                lines,
+                name_comment: name_comment.is_some(),
+            });
+            lua_line += lines;
+            if let Some(max) = options.warn_tab_lines {
+                if lines > max {
+                    report.warn(&format!(
+                        "Tab '{}' is {} lines long, more than the {} you asked for.",
+                        script_name, lines, max
+                    ));
+                }
+            }
+        }
+        // Same deal for resources: known order first, then leftovers
+        let mut resources: Vec<(String, PathBuf)> = Vec::new();
+        if options.sort_resources {
+            // ...unless they want the canonical order, which goes for
+            // everything.
+            resources.extend(std::mem::take(&mut components.rsc));
+            resources.sort_by(|(a, _), (b, _)| resource_sort_key(a).cmp(&resource_sort_key(b)));
+        } else {
+            for kind in rsc_order.lines() {
+                if let Some(path) = components.rsc.remove(kind) {
+                    resources.push((kind.to_string(), path));
+                }
+            }
+            let mut leftovers: Vec<(String, PathBuf)> =
+                std::mem::take(&mut components.rsc).into_iter().collect();
+            leftovers.sort_by(|(a, _), (b, _)| resource_sort_key(a).cmp(&resource_sort_key(b)));
+            resources.extend(leftovers);
+        }
+        let mut written_rsc: Vec<(String, PathBuf)> = Vec::new();
+        for (kind, path) in resources.iter() {
+            let is_binary = path.extension().is_some_and(|ext| ext == "p8bin");
+            let is_empty = if is_binary {
+                std::fs::metadata(path)?.len() == 0
+            } else {
+                is_blank_file(path)?
+            };
+            if !options.include_empty_sections && is_empty {
+                debug!("skipping empty resource {}", path.display());
+                continue;
+            }
+            written_rsc.push((kind.clone(), path.clone()));
+            let _section = section_span!("rsc", kind);
+            debug!("writing resource from {}", path.display());
+            writer.write_strline(&format!("__{}__", kind))?;
+            if is_binary {
+                let info = sections::lookup(kind)
+                    .filter(|info| info.hex)
+                    .ok_or_else(|| BuildError::UnknownBinaryKind(kind.clone()))?;
+                let text = sections::bytes_to_hex(&std::fs::read(path)?, info.width);
+                for line in text.lines() {
+                    writer.write_strline(line)?;
+                }
+            } else {
+                slurp(&mut writer, path, LuaTweaks::default(), report)?;
+            }
+        }
+        // flush
+        writer.flush()?;
+        if let Some(path) = &options.source_map {
+            let mut map_writer = BufWriter::new(File::create(path)?);
+            serde_json::to_writer_pretty(&mut map_writer, &source_map)?;
+            map_writer.write_all(b"\n")?;
+            map_writer.flush()?;
+        }
+        if options.verify {
+            verify_build(
+                &cart,
+                &tabs,
+                &written_rsc,
+                &stripped,
+                options.encoding,
+                LuaTweaks {
+                    trim: options.trim_trailing_whitespace,
+                    stamp: options.stamp.as_deref(),
+                    name_written: false,
+                    module: options.module_tabs,
+                },
+                report,
+            )?;
+        }
+        let warning_count = report.warnings.len() - warnings_before;
+        if options.strict && warning_count > 0 {
+            return Err(BuildError::Strict(warning_count).into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+enum AddTabError {
+    #[error("There's already a tab named '{0}'. Use --replace if you want to overwrite it.")]
+    Exists(String),
+    #[error("Can't name a tab '{name}', because it {problem}.")]
+    BadName { name: String, problem: &'static str },
+}
+
+/// Write a new tab file from some Lua source (giving it a name comment if it
+/// doesn't already start with the right one), and add it to the end of the
+/// tab order. The source is in the same encoding as the tab files.
+fn add_tab(
+    dir: &Path,
+    name: &str,
+    mut source: impl BufRead,
+    replace: bool,
+    codec: Codec,
+) -> anyhow::Result<()> {
+    // The name's about to be a filename, so it has to stay in the directory.
+    if let Some(problem) = tab_name_problem(name).or(name.trim().is_empty().then_some("is empty")) {
+        return Err(AddTabError::BadName {
+            name: name.to_string(),
+            problem,
+        }
+        .into());
+    }
+    // Same lock as build and dump, since we're about to touch the tab order.
+    let _lock = take_lock(dir)?;
+    let path = dir.join(format!("{}.lua", name));
+    let order_path = dir.join(TAB_ORDER_FILE);
+    let mut tab_order = read_optional_file_as(&order_path, codec)?;
+    let listed = tab_order
+        .lines()
+        .any(|line| tab_order_entry(line).0 == name);
+    if (listed || path.exists()) && !replace {
+        return Err(AddTabError::Exists(name.to_string()).into());
+    }
+
+    let mut bytes = Vec::new();
+    source.read_to_end(&mut bytes)?;
+    let text = codec.decode(bytes)?;
+    let mut writer = BufWriter::new(File::create(&path)?);
+    let mut first = true;
+    for line in text.lines() {
+        if first && lua_tag(line) != Some(name) {
+            writer.write_line(&codec.encode(&format!("-- {}", name))?)?;
+        }
+        first = false;
+        writer.write_line(&codec.encode(line)?)?;
+    }
+    if first {
+        // empty input, but the tab still needs its name.
+        writer.write_line(&codec.encode(&format!("-- {}", name))?)?;
+    }
+    writer.flush()?;
+
+    if !listed {
+        if !tab_order.is_empty() && !tab_order.ends_with('\n') {
+            tab_order.push('\n');
+        }
+        tab_order.push_str(name);
+        tab_order.push('\n');
+        std::fs::write(&order_path, codec.encode(&tab_order)?)?;
+    } else if tab_order
+        .lines()
+        .any(|line| line != name && tab_order_entry(line).0 == name)
+    {
+        // A deduped tab that borrowed another tab's file gets its own file
+        // now, so point its entry at that.
+        let relisted: String = tab_order
+            .lines()
+            .map(|line| {
+                if tab_order_entry(line).0 == name {
+                    format!("{}\n", name)
+                } else {
+                    format!("{}\n", line)
+                }
+            })
+            .collect();
+        std::fs::write(&order_path, codec.encode(&relisted)?)?;
+    }
+    println!("Added tab '{}' as {}", name, path.to_string_lossy());
+    Ok(())
+}
+
+/// Canonicalize the component and meta files in a dumped directory. Returns
+/// the files that actually changed; running it twice in a row changes nothing
+/// the second time.
+fn format_dir(
+    dir: &Path,
+    trim: bool,
+    canonicalize_hex: bool,
+    codec: Codec,
+    report: &mut Report,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut touched = Vec::new();
+    let components = ComponentFiles::list(dir)?;
+    let stripped_list = read_optional_file_as(dir.join(STRIPPED_FILE), codec)?;
+    let stripped: Vec<&str> = stripped_list.lines().collect();
+    for (name, path) in components.lua.iter() {
+        let original = codec.decode(std::fs::read(path)?)?;
+        let mut formatted = String::with_capacity(original.len());
+        // Only a name comment that doesn't match gets fixed. A tab with none
+        // is anonymous on purpose (say, from dump --no-synth-names), and
+        // tabs that had theirs stripped get it back at build time. (Same for
+        // ones whose name can't be a filename; they're already named.)
+        let misnamed = !stripped.contains(&name.as_str())
+            && tab_name(&original)
+                .is_some_and(|tag| tag != name && tab_name_problem(tag).is_none());
+        // The name comment is the first line that isn't blank; a wrong one
+        // gets swapped out for the right one.
+        let name_line = original.lines().position(|line| !line.trim().is_empty());
+        let name_comment = format!("-- {}", name);
+        for (i, line) in original.lines().enumerate() {
+            let line = if misnamed && Some(i) == name_line {
+                &name_comment
+            } else {
+                line
+            };
+            formatted.push_str(trim_line(line, trim));
+            formatted.push('\n');
+        }
+        if formatted != original {
+            std::fs::write(path, codec.encode(&formatted)?)?;
+            touched.push(path.clone());
+        }
+    }
+    if canonicalize_hex {
+        for (kind, path) in components.rsc.iter() {
+            // Binary files have no hex to tidy, and music isn't all hex.
+            let Some(info) = sections::lookup(kind).filter(|info| info.hex) else {
+                continue;
+            };
+            if path.extension().is_some_and(|ext| ext == "p8bin") {
+                continue;
+            }
+            let original = codec.decode(std::fs::read(path)?)?;
+            match sections::canonicalize_hex(&original, info.width) {
+                Some(formatted) if formatted != original => {
+                    std::fs::write(path, codec.encode(&formatted)?)?;
+                    touched.push(path.clone());
+                }
+                Some(_) => (),
+                None => report.warn(&format!(
+                    "{} has rows that aren't {} hex digits wide even after tidying; leaving it alone.",
+                    path.to_string_lossy(),
+                    info.width
+                )),
+            }
+        }
+    }
+    for meta in [TAB_ORDER_FILE, RSC_ORDER_FILE] {
+        let path = dir.join(meta);
+        if !path.exists() {
+            continue;
+        }
+        let original = read_optional_file_as(&path, codec)?;
+        let mut formatted = String::with_capacity(original.len());
+        for line in original.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            formatted.push_str(line);
+            formatted.push('\n');
+        }
+        if formatted != original {
+            std::fs::write(&path, codec.encode(&formatted)?)?;
+            touched.push(path);
+        }
+    }
+    Ok(touched)
+}
+
+/// Print a unified diff between an old cart and a freshly built one.
+fn print_cart_diff(old_path: &Path, old: &str, new: &str, context: usize) {
+    if old == new {
+        println!("No changes from {}.", old_path.to_string_lossy());
+        return;
+    }
+    print!("{}", cart_diff(old_path, old, new, context));
+}
+
+/// A unified diff between an old cart and a freshly built one, with this
+/// many lines of context around each change.
+fn cart_diff(old_path: &Path, old: &str, new: &str, context: usize) -> String {
+    let diff = similar::TextDiff::from_lines(old, new);
+    diff.unified_diff()
+        .context_radius(context)
+        .header(&old_path.to_string_lossy(), "(new build)")
+        .to_string()
+}
+
+/// One `#include` directive in a tab.
+struct Include {
+    /// The tab's filename.
+    tab: String,
+    /// 1-based line number within the tab.
+    line: usize,
+    /// The path as written.
+    path: String,
+    /// Whether that path exists, relative to the component dir.
+    found: bool,
+}
+
+/// Scan all the tabs in a directory for `#include` lines, in tab filename order.
+fn find_includes(dir: &Path, codec: Codec) -> anyhow::Result<Vec<Include>> {
+    let components = ComponentFiles::list(dir)?;
+    let mut tabs: Vec<(&String, &PathBuf)> = components.lua.iter().collect();
+    tabs.sort();
+    let mut includes = Vec::new();
+    for (name, path) in tabs {
+        let text = codec.decode(std::fs::read(path)?)?;
+        for (i, line) in text.lines().enumerate() {
+            if let Some(included) = include_tag(line) {
+                // `#include other.p8:2` means one tab of another cart.
+                let file = included
+                    .rsplit_once(':')
+                    .filter(|(cart, tab)| cart.ends_with(".p8") && tab.parse::<u32>().is_ok())
+                    .map_or(included, |(cart, _)| cart);
+                includes.push(Include {
+                    tab: format!("{}.lua", name),
+                    line: i + 1,
+                    path: included.to_string(),
+                    found: dir.join(file).is_file(),
+                });
+            }
+        }
+    }
+    Ok(includes)
+}
+
+/// Every file in a directory that a dump would (re)generate: the tabs, the
+/// resources, and the meta files. Sorted, for a tidy listing.
+fn clean_targets(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let components = ComponentFiles::list(dir)?;
+    let mut targets: Vec<PathBuf> = components.iter().cloned().collect();
+    for meta in [
+        TAB_ORDER_FILE,
+        RSC_ORDER_FILE,
+        P8_VERSION_FILE,
+        STRIPPED_FILE,
+    ] {
+        let path = dir.join(meta);
+        if path.is_file() {
+            targets.push(path);
+        }
+    }
+    targets.sort();
+    Ok(targets)
+}
+
+/// Something fishy in a dumped directory, and what to do about it.
+#[derive(serde::Serialize, Debug)]
+struct Finding {
+    problem: String,
+    fix: String,
+}
+
+/// Check a dumped directory for things that'll make a build surprising.
+fn diagnose_dir(
+    dir: &Path,
+    cart: Option<&Path>,
+    encoding: Encoding,
+    env: &Env,
+) -> anyhow::Result<Vec<Finding>> {
+    let mut findings = Vec::new();
+    let codec = encoding.files();
+    let components = ComponentFiles::list(dir)?;
+    let tab_order = read_optional_file_as(dir.join(TAB_ORDER_FILE), codec)?;
+    let rsc_order = read_optional_file_as(dir.join(RSC_ORDER_FILE), codec)?;
+
+    let mut names: Vec<&String> = components.lua.keys().collect();
+    names.sort();
+    for name in names {
+        if !tab_order
+            .lines()
+            .any(|line| tab_order_entry(line).0 == name)
+        {
+            findings.push(Finding {
+                problem: format!("Tab '{}' isn't listed in {}.", name, TAB_ORDER_FILE),
+                fix: format!(
+                    "Add it to {} where you want it, or it'll go after all the listed tabs.",
+                    TAB_ORDER_FILE
+                ),
+            });
+        }
+    }
+    for line in tab_order.lines().filter(|l| !l.trim().is_empty()) {
+        let (_, name) = tab_order_entry(line);
+        if !components.lua.contains_key(name) {
+            findings.push(Finding {
+                problem: format!(
+                    "{} lists tab '{}', but there's no {}.lua.",
+                    TAB_ORDER_FILE, name, name
+                ),
+                fix: format!(
+                    "Remove it from {}, or restore the file if you didn't mean to delete it.",
+                    TAB_ORDER_FILE
+                ),
+            });
         }
-        // write header
-        writer.write_strline("pico-8 cartridge // http://www.pico-8.com")?;
-        writer.write_strline(&format!("version {}", version.trim()))?;
-        // write luas
-        writer.write_strline("__lua__")?;
-        // ...btw, writing these requires some finesse, because 1. I can't
-        // guarantee there's a newline at the end of each file, and 2. I
-        // need to keep track of which file is last so we don't write an extra
-        // scissors line.
-        // Well, we'll just go line-by-line. less efficient, but safer.
-        let mut first = true;
-        // First write the known tab order
-        for script_name in tab_order.lines() {
-            if let Some(path) = components.lua.remove(script_name) {
-                if !first {
-                    // scissor line
-                    writer.write_strline("-->8")?;
-                }
-                first = false;
-                slurp_file_by_line(&mut writer, path)?;
-            }
+    }
+    let mut kinds: Vec<&String> = components.rsc.keys().collect();
+    kinds.sort();
+    for kind in kinds {
+        if !rsc_order.lines().any(|line| line == kind) {
+            findings.push(Finding {
+                problem: format!("Resource '{}' isn't listed in {}.", kind, RSC_ORDER_FILE),
+                fix: format!(
+                    "Add it to {} where you want it, or it'll go after all the listed resources.",
+                    RSC_ORDER_FILE
+                ),
+            });
         }
-        // Then leftover scripts in arbitrary order
-        for path in components.lua.values() {
-            if !first {
-                // scissor line
-                writer.write_strline("-->8")?;
+    }
+    let config = config::load(dir)?;
+    // If the version lives somewhere else, the version file doesn't matter.
+    let version_file = !(config.build.no_version_file || config.dump.no_version_file);
+    let fallback = fallback_version(None, env, config.build.assume_version)?;
+    let version = read_optional_file_as(dir.join(P8_VERSION_FILE), codec)?;
+    if !version_file {
+        debug!("not checking {}", P8_VERSION_FILE);
+    } else if version.trim().is_empty() {
+        findings.push(Finding {
+            problem: format!("{} is missing or empty.", P8_VERSION_FILE),
+            fix: format!(
+                "Builds will use version {}. If that's wrong, put the right version number in {}.",
+                fallback, P8_VERSION_FILE
+            ),
+        });
+    } else if !is_version_number(version.trim()) {
+        findings.push(Finding {
+            problem: format!(
+                "{} says version {}, which isn't a number.",
+                P8_VERSION_FILE,
+                version.trim()
+            ),
+            fix: format!(
+                "Builds will use version {} instead. Put the right version number in {}.",
+                fallback, P8_VERSION_FILE
+            ),
+        });
+    }
+    if let Some(cart) = cart.filter(|_| version_file) {
+        let text = encoding.cart().decode(std::fs::read(cart)?)?;
+        let sections = parse_sections(text.as_bytes())?;
+        let cart_version = sections.iter().find_map(|section| match section {
+            Section::Header { version, .. } => version.as_deref(),
+            _ => None,
+        });
+        let stored = version.trim();
+        if let Some(cart_version) = cart_version {
+            // (A stored version that isn't a number already got flagged.)
+            if is_version_number(stored) && stored != cart_version {
+                let older = match (cart_version.parse::<u32>(), stored.parse::<u32>()) {
+                    (Ok(c), Ok(s)) if c < s => " (older)",
+                    (Ok(c), Ok(s)) if c > s => " (newer)",
+                    _ => "",
+                };
+                findings.push(Finding {
+                    problem: format!(
+                        "{} says version {}, but {} is version {}{}.",
+                        P8_VERSION_FILE,
+                        stored,
+                        cart.to_string_lossy(),
+                        cart_version,
+                        older
+                    ),
+                    fix: format!(
+                        "If you bumped the version on purpose, update the other one to match; otherwise the next build writes version {}.",
+                        stored
+                    ),
+                });
             }
-            first = false;
-            slurp_file_by_line(&mut writer, path)?;
         }
-        // Write known resources
-        for kind in rsc_order.lines() {
-            if let Some(path) = components.rsc.remove(kind) {
-                writer.write_strline(&format!("__{}__", kind))?;
-                slurp_file_by_line(&mut writer, path)?;
+    }
+
+    let stripped_list = read_optional_file_as(dir.join(STRIPPED_FILE), codec)?;
+    let mut tabs: Vec<(&String, &PathBuf)> = components.lua.iter().collect();
+    tabs.sort();
+    for (name, path) in tabs {
+        if stripped_list.lines().any(|line| line == name) {
+            // Its first line is just code; build supplies the name.
+            continue;
+        }
+        let text = codec.decode(std::fs::read(path)?)?;
+        if let Some(tag) = tab_name(&text) {
+            // (Names that can't be filenames always land in unknown-NN.lua.)
+            if tag != name && tab_name_problem(tag).is_none() {
+                findings.push(Finding {
+                    problem: format!(
+                        "{}.lua starts with the name comment '-- {}', so it'll come back as '{}' on the next dump.",
+                        name, tag, tag
+                    ),
+                    fix: format!(
+                        "Change the first line to '-- {}', or rename the file (and its {} entry) to match.",
+                        name, TAB_ORDER_FILE
+                    ),
+                });
             }
         }
-        // Then leftover resources in arbitrary order
-        for (kind, path) in components.rsc.iter() {
-            writer.write_strline(&format!("__{}__", kind))?;
-            slurp_file_by_line(&mut writer, path)?;
+    }
+    for path in components.iter() {
+        if has_mixed_eol(&std::fs::read(path)?) {
+            findings.push(Finding {
+                problem: format!(
+                    "{} has a mix of CRLF and LF line endings. That can be a sign of a botched merge.",
+                    path.to_string_lossy()
+                ),
+                fix: "Check it over, then run `picoeater format` to normalize it.".to_string(),
+            });
         }
-        // flush
-        writer.flush()?;
-        Ok(())
     }
+    Ok(findings)
+}
+
+/// Pack all the files in a directory (not subdirectories) into a zip archive.
+fn zip_dir(dir: &Path, archive: &Path) -> anyhow::Result<()> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+    let mut zip = zip::ZipWriter::new(File::create(archive)?);
+    let options = zip::write::SimpleFileOptions::default();
+    for path in paths {
+        let Some(name) = path.file_name() else {
+            continue;
+        };
+        zip.start_file(name.to_string_lossy(), options)?;
+        std::io::copy(&mut File::open(&path)?, &mut zip)?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// Unpack a zip archive into a directory.
+fn unzip_into(archive: &Path, dir: &Path) -> anyhow::Result<()> {
+    let mut zip = zip::ZipArchive::new(File::open(archive)?)?;
+    // (extract() refuses entries that would land outside the directory.)
+    zip.extract(dir)?;
+    Ok(())
 }
 
 #[derive(Debug)]
+/// BTreeMaps, so everything that walks these goes in name order, the same
+/// every time.
 struct ComponentFiles {
-    lua: HashMap<String, PathBuf>,
-    rsc: HashMap<String, PathBuf>,
+    lua: BTreeMap<String, PathBuf>,
+    rsc: BTreeMap<String, PathBuf>,
 }
 
 fn osstr_eq_bytes(osstr: &OsStr, bytes: &[u8]) -> bool {
     osstr.as_encoded_bytes() == bytes
 }
 
-fn read_optional_text_file(path: impl AsRef<Path>) -> anyhow::Result<String> {
+/// Whether a stored version is a plain number, which is all PICO-8 writes.
+fn is_version_number(version: &str) -> bool {
+    !version.is_empty() && version.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Take the lock on a component directory, saying so if we have to wait for
+/// some other build or dump to finish with it.
+fn take_lock(dir: &Path) -> std::io::Result<Option<File>> {
+    lock::lock_dir(dir, || {
+        eprintln!(
+            "Waiting for another picoeater to finish in {}...",
+            dir.to_string_lossy()
+        )
+    })
+}
+
+/// Whether a path is a named pipe, which can only be read (or written) once.
+fn is_fifo(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        std::fs::metadata(path).is_ok_and(|meta| meta.file_type().is_fifo())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        false
+    }
+}
+
+/// An on/off flag that picoeater.toml can also turn on. The config turns it
+/// on unless the command line says otherwise either way.
+fn switch(on: bool, off: bool, config: bool) -> bool {
+    !off && (on || config)
+}
+
+/// The encoding for commands that read a dump's files (or a cart) but don't
+/// have a config table of their own: the flag, or else whatever the project
+/// dumps with, or else whatever it builds with.
+fn project_encoding(flag: Option<Encoding>, dir: &Path) -> anyhow::Result<Encoding> {
+    let config = config::load(dir)?;
+    Ok(flag
+        .or(config.dump.encoding)
+        .or(config.build.encoding)
+        .unwrap_or_default())
+}
+
+fn read_optional_file_as(path: impl AsRef<Path>, codec: Codec) -> anyhow::Result<String> {
     match std::fs::read(path.as_ref()) {
-        Ok(stuff) => Ok(String::from_utf8(stuff)?),
+        Ok(stuff) => Ok(codec.decode(stuff)?),
         Err(e) => match e.kind() {
             std::io::ErrorKind::NotFound => Ok("".to_string()),
             _ => Err(e.into()),
@@ -511,24 +3022,40 @@ fn read_optional_text_file(path: impl AsRef<Path>) -> anyhow::Result<String> {
 impl ComponentFiles {
     /// Takes an absolute directory path, finds and sorts the p8 stuff.
     fn list(dir: impl AsRef<Path>) -> std::io::Result<Self> {
-        let mut lua_map = HashMap::new();
-        let mut rsc = HashMap::new();
-        for item in std::fs::read_dir(dir.as_ref())? {
-            // If it's a lua file, put it in the vec (then later sort the vec).
-            // If it's a .p8rsc file, put it in the hashmap.
+        let mut lua_map = BTreeMap::new();
+        let mut rsc = BTreeMap::new();
+        // read_dir's order is up to the filesystem, so sort it. Then if two
+        // files ever claim the same name (gfx.p8bin and gfx.p8rsc), the
+        // same one wins every time.
+        let mut entries = std::fs::read_dir(dir.as_ref())?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            // If it's a lua file, put it in the lua map.
+            // If it's a .p8rsc or .p8bin file, put it in the rsc map.
             // If it's anything else, ignore it.
-            let entry = item?;
-            if entry.file_type()?.is_file() {
-                // doing an early allocating conversion to PathBuf so I can check
-                // file extension without having to write my own .split() for OsStr -_-
-                let path = entry.path();
+            // doing an early allocating conversion to PathBuf so I can check
+            // file extension without having to write my own .split() for OsStr -_-
+            let path = entry.path();
+            // Follow symlinks, so a shared tab can be linked into several carts.
+            // A broken link or a link loop just gets skipped.
+            let is_file = if entry.file_type()?.is_symlink() {
+                std::fs::metadata(&path).is_ok_and(|m| m.is_file())
+            } else {
+                entry.file_type()?.is_file()
+            };
+            if is_file {
+                // Skip hidden files and editor leftovers, even if they happen to
+                // end in .lua (like `.scratch.lua`, or emacs's `#main.lua#`).
+                if is_hidden_or_temp(&entry.file_name()) {
+                    continue;
+                }
                 // Skip filenames that don't have both stem and extension, they're deffo not ours.
                 let (Some(stem), Some(ext)) = (path.file_stem(), path.extension()) else {
                     continue;
                 };
                 if osstr_eq_bytes(ext, b"lua") {
                     lua_map.insert(stem.to_string_lossy().into_owned(), path);
-                } else if osstr_eq_bytes(ext, b"p8rsc") {
+                } else if osstr_eq_bytes(ext, b"p8rsc") || osstr_eq_bytes(ext, b"p8bin") {
                     rsc.insert(stem.to_string_lossy().into_owned(), path);
                 }
             }
@@ -560,3 +3087,1263 @@ impl ComponentFiles {
         lua.chain(rsc)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CART: &str = "pico-8 cartridge // http://www.pico-8.com
+version 41
+__lua__
+-- main
+print(\"hi\")
+-->8
+-- util
+function f() end
+__gfx__
+00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000
+";
+
+    /// Run picoeater with these arguments, like from the command line.
+    pub(crate) fn picoeater(args: &[&str]) -> anyhow::Result<Report> {
+        picoeater_with(&Env::from_process()?, args)
+    }
+
+    /// Same as `picoeater`, but in a made-up environment.
+    fn picoeater_with(env: &Env, args: &[&str]) -> anyhow::Result<Report> {
+        let cli = Cli::try_parse_from(std::iter::once("picoeater").chain(args.iter().copied()))?;
+        let mut report = Report::new(cli.commands.name());
+        run(cli.commands, env, &mut report).map(|()| report)
+    }
+
+    /// Dump a cart into a fresh directory and build it right back, with any
+    /// extra flags for each step. Returns the rebuilt cart's bytes.
+    pub(crate) fn round_trip(cart: &[u8], dump_flags: &[&str], build_flags: &[&str]) -> Vec<u8> {
+        let scratch = TempDir::new().unwrap();
+        let source = scratch.path().join("source.p8");
+        let dir = scratch.path().join("dir");
+        let rebuilt = scratch.path().join("rebuilt.p8");
+        std::fs::write(&source, cart).unwrap();
+        let (source, dir, out) = (arg(&source), arg(&dir), arg(&rebuilt));
+        picoeater(&[&["dump", "--dir", dir][..], dump_flags, &[source]].concat()).unwrap();
+        picoeater(&[&["build", "--dir", dir][..], build_flags, &[out]].concat()).unwrap();
+        std::fs::read(&rebuilt).unwrap()
+    }
+
+    fn arg(path: &Path) -> &str {
+        path.to_str().unwrap()
+    }
+
+    /// Write `CART` into a scratch dir and dump it, for tests that need
+    /// component files. Returns the scratch dir and the cart's path.
+    fn dumped(cart: &str) -> (TempDir, PathBuf) {
+        let scratch = TempDir::new().unwrap();
+        let path = scratch.path().join("cart.p8");
+        std::fs::write(&path, cart).unwrap();
+        picoeater(&["dump", path.to_str().unwrap()]).unwrap();
+        (scratch, path)
+    }
+
+    /// The names of a cart's tabs, in order. (Anonymous ones are "".)
+    fn tab_names(cart: &Path) -> Vec<String> {
+        let built = std::fs::read(cart).unwrap();
+        parse_sections(&built[..])
+            .unwrap()
+            .into_iter()
+            .filter_map(|section| match section {
+                Section::LuaTab { name, .. } => Some(name.unwrap_or_default()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cli_is_well_formed() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn flags_can_turn_off_what_the_config_turns_on() {
+        let (scratch, cart) = dumped(CART);
+        std::fs::write(
+            scratch.path().join(config::CONFIG_FILE),
+            "[build]\ncrlf = true\n",
+        )
+        .unwrap();
+        let cart_arg = cart.to_str().unwrap();
+
+        picoeater(&["build", cart_arg]).unwrap();
+        assert!(std::fs::read_to_string(&cart).unwrap().contains("\r\n"));
+        picoeater(&["build", "--no-crlf", cart_arg]).unwrap();
+        assert_eq!(std::fs::read_to_string(&cart).unwrap(), CART);
+        // The last one on the command line wins.
+        picoeater(&["build", "--no-crlf", "--crlf", cart_arg]).unwrap();
+        assert!(std::fs::read_to_string(&cart).unwrap().contains("\r\n"));
+        picoeater(&["build", "--crlf", "--no-crlf", cart_arg]).unwrap();
+        assert_eq!(std::fs::read_to_string(&cart).unwrap(), CART);
+    }
+
+    /// Dump `CART` to sections JSON, and hand back where it went and where
+    /// to build the new cart.
+    fn sections_json() -> (TempDir, PathBuf, PathBuf) {
+        let scratch = TempDir::new().unwrap();
+        let cart = scratch.path().join("cart.p8");
+        std::fs::write(&cart, CART).unwrap();
+        let json = scratch.path().join("cart.json");
+        let args = ["dump", "--sections-json", json.to_str().unwrap()];
+        picoeater(&[&args[..], &[cart.to_str().unwrap()]].concat()).unwrap();
+        let out = scratch.path().join("out.p8");
+        (scratch, json, out)
+    }
+
+    #[test]
+    fn from_json_round_trips() {
+        let (_scratch, json, out) = sections_json();
+        let args = ["build", "--from-json", json.to_str().unwrap()];
+        picoeater(&[&args[..], &[out.to_str().unwrap()]].concat()).unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), CART);
+    }
+
+    #[test]
+    fn from_json_uses_the_build_options() {
+        let (_scratch, json, out) = sections_json();
+        let text = std::fs::read_to_string(&json).unwrap();
+        std::fs::write(&json, text.replace("\"41\"", "null")).unwrap();
+        let (json, out) = (json.to_str().unwrap(), out.to_str().unwrap());
+
+        picoeater(&[
+            "build",
+            "--from-json",
+            json,
+            "--assume-version",
+            "38",
+            "--stamp",
+            out,
+        ])
+        .unwrap();
+        let built = std::fs::read_to_string(out).unwrap();
+        assert!(built.contains("\nversion 38\n"), "{built}");
+        assert!(built.contains("-- main\n-- built "), "{built}");
+
+        // A version that isn't a number is a warning, so --strict fails.
+        std::fs::write(json, text.replace("\"41\"", "\"beta\"")).unwrap();
+        let err = picoeater(&["build", "--from-json", json, "--strict", out]).unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(BuildError::Strict(1))),
+            "{err}"
+        );
+
+        std::fs::write(json, text.replace("0000000000", "00000")).unwrap();
+        let err = picoeater(&["build", "--from-json", json, "--validate", out]).unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(BuildError::RowWidth { .. })),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn from_json_refuses_component_file_options() {
+        for flag in ["--verify", "--warn-mixed-eol", "--include-empty-sections"] {
+            let args = ["build", "--from-json", "cart.json", flag, "out.p8"];
+            let err = picoeater(&args).unwrap_err();
+            assert!(err.downcast_ref::<clap::Error>().is_some(), "{flag}: {err}");
+        }
+    }
+
+    #[test]
+    fn concurrent_builds_take_turns() {
+        let (scratch, cart) = dumped(CART);
+        for _ in 0..5 {
+            let builds: Vec<_> = (0..2)
+                .map(|_| {
+                    let cart = cart.clone();
+                    std::thread::spawn(move || {
+                        picoeater(&["build", cart.to_str().unwrap()]).map(|_| ())
+                    })
+                })
+                .collect();
+            for build in builds {
+                build.join().unwrap().unwrap();
+            }
+            assert_eq!(std::fs::read_to_string(&cart).unwrap(), CART);
+        }
+        let leftovers: Vec<_> = std::fs::read_dir(scratch.path())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().contains("lock"))
+            .collect();
+        assert!(leftovers.is_empty());
+    }
+
+    #[test]
+    fn no_clobber_wont_delete_the_other_resource_format() {
+        let scratch = TempDir::new().unwrap();
+        let cart = scratch.path().join("cart.p8");
+        std::fs::write(&cart, CART).unwrap();
+        let theirs = scratch.path().join("gfx.p8bin");
+        std::fs::write(&theirs, "not ours").unwrap();
+
+        let err = picoeater(&["dump", "--no-clobber", cart.to_str().unwrap()]).unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(DumpError::Clobber(_))),
+            "{err}"
+        );
+        assert_eq!(std::fs::read_to_string(&theirs).unwrap(), "not ours");
+    }
+
+    #[test]
+    fn no_clobber_wont_delete_a_deduped_tab() {
+        let scratch = TempDir::new().unwrap();
+        let cart = scratch.path().join("cart.p8");
+        let twins = "pico-8 cartridge // http://www.pico-8.com\nversion 41\n__lua__\nprint(1)\n-->8\nprint(1)\n";
+        std::fs::write(&cart, twins).unwrap();
+        let theirs = scratch.path().join("unknown-01.lua");
+        std::fs::write(&theirs, "not ours").unwrap();
+
+        let args = ["dump", "--no-clobber", "--dedupe-tabs", "--no-synth-names"];
+        let cart = cart.to_str().unwrap();
+        let err = picoeater(&[&args[..], &[cart]].concat()).unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(DumpError::Clobber(_))),
+            "{err}"
+        );
+        assert_eq!(std::fs::read_to_string(&theirs).unwrap(), "not ours");
+    }
+
+    #[test]
+    fn format_leaves_anonymous_tabs_anonymous() {
+        let scratch = TempDir::new().unwrap();
+        let cart = scratch.path().join("cart.p8");
+        let anon = "pico-8 cartridge // http://www.pico-8.com\nversion 41\n__lua__\nprint(1)\n";
+        std::fs::write(&cart, anon).unwrap();
+        picoeater(&["dump", "--no-synth-names", cart.to_str().unwrap()]).unwrap();
+        let tab = scratch.path().join("unknown-00.lua");
+        assert_eq!(std::fs::read_to_string(&tab).unwrap(), "print(1)\n");
+
+        let dir = scratch.path().to_str().unwrap();
+        picoeater(&["format", "--dir", dir]).unwrap();
+        assert_eq!(std::fs::read_to_string(&tab).unwrap(), "print(1)\n");
+    }
+
+    #[test]
+    fn format_fixes_a_mismatched_name_once() {
+        let (scratch, _) = dumped(CART);
+        let tab = scratch.path().join("util.lua");
+        std::fs::write(&tab, "-- utils\r\nfunction f() end").unwrap();
+        let dir = scratch.path().to_str().unwrap();
+
+        picoeater(&["format", "--dir", dir]).unwrap();
+        let formatted = std::fs::read_to_string(&tab).unwrap();
+        assert_eq!(formatted, "-- util\nfunction f() end\n");
+        assert!(format_dir(
+            scratch.path(),
+            false,
+            false,
+            Codec::Utf8,
+            &mut Report::new("format")
+        )
+        .unwrap()
+        .is_empty());
+
+        // Blank lines above the name stay put, and a block comment name gets
+        // replaced the same way.
+        std::fs::write(&tab, "\n--[[ utils ]]\n-- utils\n").unwrap();
+        picoeater(&["format", "--dir", dir]).unwrap();
+        let formatted = std::fs::read_to_string(&tab).unwrap();
+        assert_eq!(formatted, "\n-- util\n-- utils\n");
+    }
+
+    /// A dir with one Latin-1 tab in it, CRLFs and all.
+    fn latin1_dir() -> (TempDir, PathBuf) {
+        let scratch = TempDir::new().unwrap();
+        let tab = scratch.path().join("main.lua");
+        std::fs::write(&tab, b"-- main\r\nprint(\"caf\xe9\")\r\n").unwrap();
+        std::fs::write(scratch.path().join(TAB_ORDER_FILE), "main\n").unwrap();
+        (scratch, tab)
+    }
+
+    #[test]
+    fn format_reads_latin1_tabs() {
+        let (scratch, tab) = latin1_dir();
+        let dir = scratch.path().to_str().unwrap();
+        picoeater(&["format", "--encoding", "latin1", "--dir", dir]).unwrap();
+        assert_eq!(
+            std::fs::read(&tab).unwrap(),
+            b"-- main\nprint(\"caf\xe9\")\n"
+        );
+    }
+
+    #[test]
+    fn latin1_from_the_config_reaches_every_reader() {
+        let (scratch, tab) = latin1_dir();
+        std::fs::write(
+            scratch.path().join(config::CONFIG_FILE),
+            "[dump]\nencoding = \"latin1\"\n",
+        )
+        .unwrap();
+        let dir = scratch.path().to_str().unwrap();
+        for command in ["format", "doctor", "includes"] {
+            picoeater(&[command, "--dir", dir]).unwrap();
+        }
+        assert_eq!(
+            std::fs::read(&tab).unwrap(),
+            b"-- main\nprint(\"caf\xe9\")\n"
+        );
+
+        let source: &[u8] = b"print(\"\xe9t\xe9\")\n";
+        add_tab(scratch.path(), "more", source, false, Codec::Latin1).unwrap();
+        let added = std::fs::read(scratch.path().join("more.lua")).unwrap();
+        assert_eq!(added, b"-- more\nprint(\"\xe9t\xe9\")\n");
+    }
+
+    #[test]
+    fn add_tab_refuses_names_that_escape_the_dir() {
+        let scratch = TempDir::new().unwrap();
+        let dir = scratch.path().join("cart");
+        std::fs::create_dir(&dir).unwrap();
+        for name in ["../x", "a/b", "..", ".hidden", ""] {
+            let err = add_tab(&dir, name, "print(1)\n".as_bytes(), false, Codec::Utf8).unwrap_err();
+            assert!(
+                matches!(err.downcast_ref(), Some(AddTabError::BadName { .. })),
+                "{name:?}: {err}"
+            );
+        }
+        assert!(!scratch.path().join("x.lua").exists());
+        assert!(!dir.join(TAB_ORDER_FILE).exists());
+
+        add_tab(&dir, "main", "print(1)\n".as_bytes(), false, Codec::Utf8).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("main.lua")).unwrap(),
+            "-- main\nprint(1)\n"
+        );
+    }
+
+    #[test]
+    fn synthesized_names_stick_after_one_round_trip() {
+        let anon =
+            "pico-8 cartridge // http://www.pico-8.com\nversion 41\n__lua__\nprint(1)\n-->8\nx=1\n";
+        let once = round_trip(anon.as_bytes(), &[], &[]);
+        assert_eq!(
+            String::from_utf8_lossy(&once),
+            "pico-8 cartridge // http://www.pico-8.com\nversion 41\n__lua__\n-- unknown-00\nprint(1)\n-->8\n-- unknown-01\nx=1\n"
+        );
+        let twice = round_trip(&once, &[], &[]);
+        assert_eq!(twice, once);
+
+        // Or they never show up at all.
+        let once = round_trip(anon.as_bytes(), &["--no-synth-names"], &[]);
+        assert_eq!(String::from_utf8_lossy(&once), anon);
+        assert_eq!(round_trip(&once, &["--no-synth-names"], &[]), once);
+    }
+
+    #[test]
+    fn crlf_builds_end_every_line_in_crlf() {
+        let built = round_trip(CART.as_bytes(), &[], &["--crlf"]);
+        let built = String::from_utf8(built).unwrap();
+        assert!(built.ends_with("\r\n"));
+        for line in built.split_inclusive('\n') {
+            assert!(line.ends_with("\r\n"), "{line:?}");
+        }
+        assert_eq!(built.replace("\r\n", "\n"), CART);
+
+        // And a CRLF cart dumps to the same files an LF one does.
+        assert_eq!(round_trip(built.as_bytes(), &[], &[]), CART.as_bytes());
+        assert_eq!(
+            round_trip(built.as_bytes(), &[], &["--crlf"]),
+            built.as_bytes()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_tabs_get_built() {
+        use std::os::unix::fs::symlink;
+        let (scratch, cart) = dumped(CART);
+        let shared = TempDir::new().unwrap();
+        let util = scratch.path().join("util.lua");
+        std::fs::rename(&util, shared.path().join("util.lua")).unwrap();
+        symlink(shared.path().join("util.lua"), &util).unwrap();
+        // Links that go nowhere don't count, and don't stop the build.
+        symlink("loop.lua", scratch.path().join("loop.lua")).unwrap();
+        symlink("nowhere.lua", scratch.path().join("gone.lua")).unwrap();
+        std::fs::remove_file(&cart).unwrap();
+
+        picoeater(&["build", cart.to_str().unwrap()]).unwrap();
+        assert_eq!(std::fs::read_to_string(&cart).unwrap(), CART);
+        let found = ComponentFiles::list(scratch.path()).unwrap();
+        assert_eq!(found.lua.keys().collect::<Vec<_>>(), ["main", "util"]);
+    }
+
+    #[test]
+    fn resources_before_the_lua_come_back_after_it() {
+        let header = "pico-8 cartridge // http://www.pico-8.com\nversion 41\n";
+        let cart = format!("{header}__map__\n11\n__gfx__\n0000\n__lua__\n-- main\n");
+        let rebuilt = round_trip(cart.as_bytes(), &[], &[]);
+        assert_eq!(
+            String::from_utf8_lossy(&rebuilt),
+            format!("{header}__lua__\n-- main\n__map__\n11\n__gfx__\n0000\n")
+        );
+    }
+
+    #[test]
+    fn empty_resource_files_only_build_when_asked() {
+        let (scratch, cart) = dumped(CART);
+        let without_gfx = CART.split("__gfx__").next().unwrap();
+        let gfx = scratch.path().join("gfx.p8rsc");
+        let cart_arg = cart.to_str().unwrap();
+
+        std::fs::write(&gfx, "").unwrap();
+        picoeater(&["build", cart_arg]).unwrap();
+        assert_eq!(std::fs::read_to_string(&cart).unwrap(), without_gfx);
+        picoeater(&["build", "--include-empty-sections", cart_arg]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&cart).unwrap(),
+            format!("{without_gfx}__gfx__\n")
+        );
+
+        // A file that isn't there at all isn't an empty section, it's no section.
+        std::fs::remove_file(&gfx).unwrap();
+        for flags in [&[][..], &["--include-empty-sections"]] {
+            picoeater(&[&["build"][..], flags, &[cart_arg]].concat()).unwrap();
+            assert_eq!(std::fs::read_to_string(&cart).unwrap(), without_gfx);
+        }
+    }
+
+    #[test]
+    fn a_cart_without_a_version_gets_the_default() {
+        let (scratch, cart) = dumped(&CART.replace("version 41\n", ""));
+        assert!(!scratch.path().join(P8_VERSION_FILE).exists());
+        picoeater(&["build", cart.to_str().unwrap()]).unwrap();
+        assert_eq!(std::fs::read_to_string(&cart).unwrap(), CART);
+    }
+
+    #[test]
+    fn empty_tab_files_are_left_out_of_the_build() {
+        let three = "pico-8 cartridge // http://www.pico-8.com\nversion 41\n__lua__\n-- a\nx=1\n-->8\n-- b\ny=2\n-->8\n-- c\nz=3\n";
+        for empty in ["a", "b", "c"] {
+            let (scratch, cart) = dumped(three);
+            std::fs::write(scratch.path().join(format!("{empty}.lua")), " \n\n").unwrap();
+
+            let report = picoeater(&["build", cart.to_str().unwrap()]).unwrap();
+            assert_eq!(report.warnings.len(), 1, "{empty}: {:?}", report.warnings);
+            let expected: Vec<_> = ["a", "b", "c"]
+                .into_iter()
+                .filter(|n| *n != empty)
+                .collect();
+            assert_eq!(tab_names(&cart), expected);
+        }
+    }
+
+    #[test]
+    fn dedupe_tabs_writes_twins_once() {
+        let twins = "pico-8 cartridge // http://www.pico-8.com\nversion 41\n__lua__\n-- lib\nx=1\n-->8\n-- main\n-->8\n-- lib\nx=1\n";
+        let scratch = TempDir::new().unwrap();
+        let cart = scratch.path().join("cart.p8");
+        std::fs::write(&cart, twins).unwrap();
+        let cart = cart.to_str().unwrap();
+        picoeater(&["dump", "--dedupe-tabs", cart]).unwrap();
+
+        let order = std::fs::read_to_string(scratch.path().join(TAB_ORDER_FILE)).unwrap();
+        assert_eq!(order, "lib\nmain\nlib-again\tlib\n");
+        assert!(!scratch.path().join("lib-again.lua").exists());
+        picoeater(&["build", cart]).unwrap();
+        assert_eq!(std::fs::read_to_string(cart).unwrap(), twins);
+    }
+
+    #[test]
+    fn tab_filters_pick_tabs_and_excludes_win() {
+        let cart = "pico-8 cartridge // http://www.pico-8.com\nversion 41\n__lua__\n-- main\n-->8\n-- debug_draw\n-->8\n-- debug_log\n-->8\n-- player\n";
+        let (_scratch, path) = dumped(cart);
+        let path = path.to_str().unwrap();
+        let tabs_after = |flags: &[&str]| {
+            picoeater(&[&["build"][..], flags, &[path]].concat()).unwrap();
+            tab_names(Path::new(path))
+        };
+
+        assert_eq!(
+            tabs_after(&["--exclude-tabs", "debug_*"]),
+            ["main", "player"]
+        );
+        assert_eq!(
+            tabs_after(&["--include-tabs", "debug_*", "--include-tabs", "main"]),
+            ["main", "debug_draw", "debug_log"]
+        );
+        assert_eq!(
+            tabs_after(&["--include-tabs", "debug_*", "--exclude-tabs", "*_log"]),
+            ["debug_draw"]
+        );
+        assert_eq!(tabs_after(&[]).len(), 4);
+    }
+
+    #[test]
+    fn uppercase_resource_kinds_keep_their_case() {
+        let upper = CART.replace("__gfx__", "__GFX__") + "__sfx__\n00\n";
+        let (scratch, cart) = dumped(&upper);
+        assert!(scratch.path().join("GFX.p8rsc").exists());
+        let order = std::fs::read_to_string(scratch.path().join(RSC_ORDER_FILE)).unwrap();
+        assert_eq!(order, "GFX\nsfx\n");
+        let cart_arg = cart.to_str().unwrap();
+        picoeater(&["build", cart_arg]).unwrap();
+        assert_eq!(std::fs::read_to_string(&cart).unwrap(), upper);
+
+        // It's not the gfx PICO-8 knows, so sorting puts it after sfx.
+        picoeater(&["build", "--sort-resources", cart_arg]).unwrap();
+        let sorted = std::fs::read_to_string(&cart).unwrap();
+        assert!(sorted.find("__sfx__") < sorted.find("__GFX__"), "{sorted}");
+    }
+
+    #[test]
+    fn a_full_label_round_trips_and_validates() {
+        let digits = "0123456789abcdefghijklmnopqrstuv";
+        let row: String = digits.chars().cycle().take(128).collect();
+        let label = format!("{row}\n").repeat(128);
+        let sfx = "0".repeat(168);
+        let cart = format!("{CART}__label__\n{label}\n__sfx__\n{sfx}\n");
+        assert_eq!(round_trip(cart.as_bytes(), &[], &[]), cart.as_bytes());
+        assert_eq!(
+            round_trip(cart.as_bytes(), &[], &["--validate"]),
+            cart.as_bytes()
+        );
+
+        // The blank line after it is fine, but a short row isn't.
+        let shown = Path::new("label.p8rsc");
+        assert!(validate_rsc("label", &format!("{label}\n"), shown).is_ok());
+        let short = label.replacen(&row, "0", 1);
+        assert!(validate_rsc("label", &short, shown).is_err());
+    }
+
+    #[test]
+    fn stripped_name_comments_come_back_on_build() {
+        // A second line that looks like a name, and a tab that's nothing but
+        // its name.
+        let cart = "pico-8 cartridge // http://www.pico-8.com\nversion 41\n__lua__\n-- main\n-- util\nx=1\n-->8\n-- util\n-->8\nanon=1\n";
+        let scratch = TempDir::new().unwrap();
+        let path = scratch.path().join("cart.p8");
+        std::fs::write(&path, cart).unwrap();
+        let path = path.to_str().unwrap();
+        picoeater(&["dump", "--strip-name-comment", "--no-synth-names", path]).unwrap();
+
+        let read = |name: &str| std::fs::read_to_string(scratch.path().join(name)).unwrap();
+        assert_eq!(read("main.lua"), "-- util\nx=1\n");
+        assert_eq!(read("util.lua"), "");
+        assert_eq!(read("unknown-02.lua"), "anon=1\n");
+        assert_eq!(read(STRIPPED_FILE), "main\nutil\n");
+        picoeater(&["build", path]).unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), cart);
+    }
+
+    #[test]
+    fn hidden_and_temp_files_are_not_components() {
+        let (scratch, cart) = dumped(CART);
+        let leftovers = [
+            ".scratch.lua",
+            "main.lua.swp",
+            "main.lua~",
+            "#main.lua#",
+            ".gfx.p8rsc",
+        ];
+        for name in leftovers {
+            std::fs::write(scratch.path().join(name), "oops\n").unwrap();
+        }
+        let cart_arg = cart.to_str().unwrap();
+        picoeater(&["build", cart_arg]).unwrap();
+        assert_eq!(std::fs::read_to_string(&cart).unwrap(), CART);
+
+        picoeater(&["dump", "--purge", cart_arg]).unwrap();
+        for name in leftovers {
+            assert!(scratch.path().join(name).exists(), "{name}");
+        }
+        assert!(!clean_targets(scratch.path())
+            .unwrap()
+            .iter()
+            .any(|path| leftovers.iter().any(|name| path.ends_with(name))));
+    }
+
+    #[test]
+    fn sfx_banks_round_trip_and_sort_after_sfx() {
+        let banks = "pico-8 cartridge // http://www.pico-8.com\nversion 41\n__lua__\n-- main\n__music__\n00 41424344\n__sfx10__\n10\n__sfx1__\n01\n__sfx__\n00\n__sfx0__\n00\n";
+        let (scratch, cart) = dumped(banks);
+        let cart_arg = cart.to_str().unwrap();
+        picoeater(&["build", cart_arg]).unwrap();
+        assert_eq!(std::fs::read_to_string(&cart).unwrap(), banks);
+
+        let sorted = "pico-8 cartridge // http://www.pico-8.com\nversion 41\n__lua__\n-- main\n__sfx__\n00\n__sfx0__\n00\n__sfx1__\n01\n__sfx10__\n10\n__music__\n00 41424344\n";
+        picoeater(&["build", "--sort-resources", cart_arg]).unwrap();
+        assert_eq!(std::fs::read_to_string(&cart).unwrap(), sorted);
+        // Files the resource order doesn't mention get sorted the same way.
+        std::fs::write(scratch.path().join(RSC_ORDER_FILE), "music\n").unwrap();
+        picoeater(&["build", cart_arg]).unwrap();
+        let music = "__music__\n00 41424344\n";
+        assert_eq!(
+            std::fs::read_to_string(&cart).unwrap(),
+            sorted
+                .replace(music, "")
+                .replace("__sfx__", &format!("{music}__sfx__"))
+        );
+    }
+
+    #[test]
+    fn trailing_whitespace_only_comes_off_the_lua() {
+        let messy = "pico-8 cartridge // http://www.pico-8.com\nversion 41\n__lua__\n-- main  \nprint(1)\t \n__music__\n00 41424344 \n";
+        let tidy = "pico-8 cartridge // http://www.pico-8.com\nversion 41\n__lua__\n-- main\nprint(1)\n__music__\n00 41424344 \n";
+        assert_eq!(
+            String::from_utf8(round_trip(
+                messy.as_bytes(),
+                &[],
+                &["--trim-trailing-whitespace"]
+            ))
+            .unwrap(),
+            tidy
+        );
+
+        let (scratch, _) = dumped(messy);
+        let dir = scratch.path().to_str().unwrap();
+        picoeater(&["format", "--trim-trailing-whitespace", "--dir", dir]).unwrap();
+        let read = |name: &str| std::fs::read_to_string(scratch.path().join(name)).unwrap();
+        assert_eq!(read("main.lua"), "-- main\nprint(1)\n");
+        assert_eq!(read("music.p8rsc"), "00 41424344 \n");
+    }
+
+    #[test]
+    fn default_cart_skips_directories_named_like_carts() {
+        let scratch = TempDir::new().unwrap();
+        std::fs::create_dir(scratch.path().join("weird.p8")).unwrap();
+        let err = get_default_p8(scratch.path()).unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(DefaultP8Error::Zero)),
+            "{err}"
+        );
+
+        let cart = scratch.path().join("cart.p8");
+        std::fs::write(&cart, CART).unwrap();
+        assert_eq!(get_default_p8(scratch.path()).unwrap(), cart);
+    }
+
+    #[test]
+    fn strict_dumps_fail_on_a_truncated_cart() {
+        let scratch = TempDir::new().unwrap();
+        let cart = scratch.path().join("cart.p8");
+        std::fs::write(&cart, format!("{CART}0000\n")).unwrap();
+        let cart = cart.to_str().unwrap();
+        let report = picoeater(&["dump", cart]).unwrap();
+        assert_eq!(report.warnings.len(), 1, "{:?}", report.warnings);
+        let err = picoeater(&["dump", "--strict", cart]).unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(DumpError::Strict(1))),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn component_listing_order_is_by_name() {
+        let scratch = TempDir::new().unwrap();
+        for name in ["zeta", "alpha", "mid"] {
+            let tab = scratch.path().join(format!("{name}.lua"));
+            std::fs::write(tab, format!("-- {name}\n")).unwrap();
+        }
+        for name in ["gfx.p8rsc", "gfx.p8bin"] {
+            std::fs::write(scratch.path().join(name), "00\n").unwrap();
+        }
+        let found = ComponentFiles::list(scratch.path()).unwrap();
+        assert_eq!(
+            found.lua.keys().collect::<Vec<_>>(),
+            ["alpha", "mid", "zeta"]
+        );
+        // When two files claim the same kind, it's always the same one.
+        assert_eq!(found.rsc["gfx"], scratch.path().join("gfx.p8rsc"));
+
+        // Tabs the tab order doesn't mention go in by name, too.
+        let cart = scratch.path().join("cart.p8");
+        picoeater(&["build", cart.to_str().unwrap()]).unwrap();
+        assert_eq!(tab_names(&cart), ["alpha", "mid", "zeta"]);
+    }
+
+    #[test]
+    fn an_empty_last_section_dumps_to_an_empty_file() {
+        let cart = format!("{CART}__music__\n");
+        let (scratch, path) = dumped(&cart);
+        let music = std::fs::read_to_string(scratch.path().join("music.p8rsc")).unwrap();
+        assert_eq!(music, "");
+        let order = std::fs::read_to_string(scratch.path().join(RSC_ORDER_FILE)).unwrap();
+        assert_eq!(order, "gfx\nmusic\n");
+
+        let path = path.to_str().unwrap();
+        picoeater(&["build", "--include-empty-sections", path]).unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), cart);
+    }
+
+    #[test]
+    fn input_glob_dumps_each_cart_into_its_own_dir() {
+        let scratch = TempDir::new().unwrap();
+        let carts = scratch.path().join("carts");
+        std::fs::create_dir(&carts).unwrap();
+        std::fs::write(carts.join("one.p8"), CART).unwrap();
+        let two = CART.replace("-- util", "-- other");
+        std::fs::write(carts.join("two.p8"), &two).unwrap();
+        std::fs::write(carts.join("notes.txt"), "not a cart").unwrap();
+
+        let pattern = carts.join("*.p8");
+        let out = scratch.path().join("out");
+        let args = ["dump", "--input-glob", arg(&pattern), "--dir", arg(&out)];
+        picoeater(&args).unwrap();
+        let mut dirs: Vec<_> = std::fs::read_dir(&out)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        dirs.sort();
+        assert_eq!(dirs, ["one", "two"]);
+        for (name, cart) in [("one", CART), ("two", &two)] {
+            let rebuilt = scratch.path().join(format!("{name}.p8"));
+            let dir = out.join(name);
+            picoeater(&["build", "--dir", arg(&dir), arg(&rebuilt)]).unwrap();
+            assert_eq!(std::fs::read_to_string(&rebuilt).unwrap(), cart);
+        }
+
+        let err = picoeater(&["dump", "--input-glob", arg(&carts.join("*.p9"))]).unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(DumpError::NoGlobMatches(_))),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn canonical_names_rebuild_exactly() {
+        let anon =
+            "pico-8 cartridge // http://www.pico-8.com\nversion 41\n__lua__\nprint(1)\n-->8\nx=1\n";
+        let flags = ["--canonical-names", "--no-synth-names"];
+        assert_eq!(round_trip(anon.as_bytes(), &flags, &[]), anon.as_bytes());
+        let once = round_trip(anon.as_bytes(), &["--canonical-names"], &[]);
+        assert!(String::from_utf8_lossy(&once).contains("\n-- tab-"));
+        assert_eq!(round_trip(&once, &["--canonical-names"], &[]), once);
+    }
+
+    #[test]
+    fn format_canonicalizes_hex_files() {
+        let (scratch, cart) = dumped(CART);
+        let gfx = scratch.path().join("gfx.p8rsc");
+        let row = "0".repeat(128);
+        std::fs::write(&gfx, format!("{} {}\n", &row[..64], &row[64..])).unwrap();
+        let map = scratch.path().join("map.p8rsc");
+        std::fs::write(&map, "00 AB\n").unwrap();
+        let dir = scratch.path().to_str().unwrap();
+
+        let report = picoeater(&["format", "--canonicalize-hex", "--dir", dir]).unwrap();
+        assert_eq!(std::fs::read_to_string(&gfx).unwrap(), format!("{row}\n"));
+        // A row that's still too short gets a warning, and no changes.
+        assert_eq!(report.warnings.len(), 1, "{:?}", report.warnings);
+        assert_eq!(std::fs::read_to_string(&map).unwrap(), "00 AB\n");
+        picoeater(&["build", cart.to_str().unwrap()]).unwrap();
+        assert!(std::fs::read_to_string(&cart).unwrap().starts_with(CART));
+    }
+
+    #[test]
+    fn manifest_lists_exactly_the_dumped_files() {
+        let scratch = TempDir::new().unwrap();
+        let cart = scratch.path().join("cart.p8");
+        std::fs::write(&cart, CART).unwrap();
+        let dir = scratch.path().join("dir");
+        let on_disk = |dir: &Path| {
+            let mut files: Vec<PathBuf> = std::fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect();
+            files.sort();
+            files
+        };
+
+        let text = scratch.path().join("files.txt");
+        let args = [
+            "dump",
+            "--dir",
+            arg(&dir),
+            "--manifest",
+            arg(&text),
+            arg(&cart),
+        ];
+        picoeater(&args).unwrap();
+        let mut listed: Vec<PathBuf> = std::fs::read_to_string(&text)
+            .unwrap()
+            .lines()
+            .map(PathBuf::from)
+            .collect();
+        listed.sort();
+        assert_eq!(listed, on_disk(&dir));
+
+        let json = scratch.path().join("files.json");
+        let args = [
+            "dump",
+            "--dir",
+            arg(&dir),
+            "--manifest",
+            arg(&json),
+            arg(&cart),
+        ];
+        picoeater(&args).unwrap();
+        let mut listed: Vec<PathBuf> =
+            serde_json::from_str(&std::fs::read_to_string(&json).unwrap()).unwrap();
+        listed.sort();
+        assert_eq!(listed, on_disk(&dir));
+    }
+
+    #[test]
+    fn cart_name_can_come_from_a_tab() {
+        let (scratch, _) = dumped(CART);
+        let util = scratch.path().join("util.lua");
+        let fallback = scratch.path().join("fallback.p8");
+        let build = || {
+            let args = [
+                "build",
+                "--dir",
+                arg(scratch.path()),
+                "--cart-name-from-tab",
+                "util",
+            ];
+            picoeater(&[&args[..], &[arg(&fallback)]].concat()).unwrap()
+        };
+
+        std::fs::write(&util, "-- util\n  --  @cart my game\nfunction f() end\n").unwrap();
+        build();
+        assert!(scratch.path().join("my game.p8").exists());
+        assert!(!fallback.exists());
+
+        // No @cart line, or a name that can't be a filename, means the usual
+        // cart.
+        std::fs::write(&util, "-- util\n-- @cartridge x\n").unwrap();
+        assert!(build().warnings.is_empty());
+        assert!(fallback.exists());
+        std::fs::remove_file(&fallback).unwrap();
+        std::fs::write(&util, "-- util\n-- @cart ../escape\n").unwrap();
+        assert_eq!(build().warnings.len(), 1);
+        assert!(fallback.exists());
+    }
+
+    #[test]
+    fn first_build_names_the_cart_after_the_dir() {
+        let scratch = TempDir::new().unwrap();
+        let dir = scratch.path().join("mygame");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("main.lua"), "-- main\n").unwrap();
+        picoeater(&["build", "--dir", arg(&dir)]).unwrap();
+        assert!(dir.join("mygame.p8").is_file());
+        // Now that there's a cart, it's the one that gets built.
+        picoeater(&["build", "--dir", arg(&dir)]).unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+        let dir = scratch.path().join("named");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("main.lua"), "-- main\n").unwrap();
+        let config = "[build]\ncart_name = \"other\"\n";
+        std::fs::write(dir.join(config::CONFIG_FILE), config).unwrap();
+        picoeater(&["build", "--dir", arg(&dir)]).unwrap();
+        assert!(dir.join("other.p8").is_file());
+        assert!(!dir.join("named.p8").exists());
+    }
+
+    #[test]
+    fn lines_that_look_like_tags_get_a_warning() {
+        let (scratch, cart) = dumped(CART);
+        let cart = cart.to_str().unwrap();
+        let gfx = scratch.path().join("gfx.p8rsc");
+        let util = scratch.path().join("util.lua");
+        let warnings = || picoeater(&["build", cart]).unwrap().warnings;
+        assert!(warnings().is_empty());
+
+        std::fs::write(&gfx, "00\n__map__\n11\n-->8\n").unwrap();
+        let found = warnings();
+        assert_eq!(found.len(), 1, "{found:?}");
+        assert!(
+            found[0].contains("gfx.p8rsc, line 2: '__map__'"),
+            "{found:?}"
+        );
+
+        std::fs::write(&util, "-- util\n-->8\nx=1\n__lua__\n").unwrap();
+        let found = warnings();
+        assert_eq!(found.len(), 3, "{found:?}");
+        assert!(found
+            .iter()
+            .any(|w| w.contains("util.lua, line 4: '__lua__'")));
+        assert!(found.iter().any(|w| w.contains("util.lua, line 2: a -->8")));
+    }
+
+    #[test]
+    fn dump_makes_its_destination_unless_told_not_to() {
+        let scratch = TempDir::new().unwrap();
+        let cart = scratch.path().join("cart.p8");
+        std::fs::write(&cart, CART).unwrap();
+        let nested = scratch.path().join("a/b/c");
+
+        let args = [
+            "dump",
+            "--dest-must-exist",
+            "--dir",
+            arg(&nested),
+            arg(&cart),
+        ];
+        let err = picoeater(&args).unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(DumpError::NoDest(_))),
+            "{err}"
+        );
+        assert!(!scratch.path().join("a").exists());
+
+        picoeater(&["dump", "--dir", arg(&nested), arg(&cart)]).unwrap();
+        assert!(nested.join("main.lua").is_file());
+    }
+
+    #[test]
+    fn module_tabs_wrap_each_tab_below_its_name() {
+        let built = round_trip(CART.as_bytes(), &[], &["--module-tabs"]);
+        let expected = CART
+            .replace("-- main\n", "-- main\ndo\n")
+            .replace("-- util\n", "-- util\ndo\n")
+            .replace("\n-->8", "\nend\n-->8")
+            .replace("\n__gfx__", "\nend\n__gfx__");
+        assert_eq!(String::from_utf8(built).unwrap(), expected);
+
+        // The stamp stays up top with the name, and an anonymous tab just
+        // gets wrapped.
+        let stamp = build_stamp();
+        let tweaks = LuaTweaks {
+            stamp: Some(&stamp),
+            module: true,
+            ..LuaTweaks::default()
+        };
+        assert_eq!(
+            tweaks.apply("\n-- main\nx=1\n"),
+            ["", "-- main", &stamp, "do", "x=1", "end"]
+        );
+        let tweaks = LuaTweaks {
+            module: true,
+            ..LuaTweaks::default()
+        };
+        assert_eq!(tweaks.apply("x=1\n"), ["do", "x=1", "end"]);
+    }
+
+    #[test]
+    fn diff_context_sets_the_lines_around_a_change() {
+        let old: String = (1..=20).map(|i| format!("line {i}\n")).collect();
+        let new = old.replace("line 10\n", "line ten\n");
+        let context_lines = |context| {
+            cart_diff(Path::new("old.p8"), &old, &new, context)
+                .lines()
+                .filter(|line| line.starts_with(' '))
+                .count()
+        };
+        assert_eq!(context_lines(3), 6);
+        assert_eq!(context_lines(1), 2);
+        assert_eq!(context_lines(0), 0);
+        // All the context there is, which is what --no-context asks for.
+        assert_eq!(context_lines(20), 19);
+
+        // It can't be mixed with --diff-context, though.
+        let scratch = TempDir::new().unwrap();
+        let cart = scratch.path().join("cart.p8");
+        std::fs::write(&cart, &old).unwrap();
+        std::fs::write(scratch.path().join("main.lua"), &new).unwrap();
+        let args = [
+            "build",
+            "--compare-with",
+            arg(&cart),
+            "--no-context",
+            arg(&cart),
+        ];
+        picoeater(&args).unwrap();
+        let args = [
+            "build",
+            "--compare-with",
+            arg(&cart),
+            "--no-context",
+            "--diff-context",
+            "2",
+            arg(&cart),
+        ];
+        let err = picoeater(&args).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<clap::Error>().map(clap::Error::kind),
+            Some(clap::error::ErrorKind::ArgumentConflict),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn add_tab_knows_deduped_names() {
+        let twins = "pico-8 cartridge // http://www.pico-8.com\nversion 41\n__lua__\n-- lib\nx=1\n-->8\n-- lib\nx=1\n";
+        let scratch = TempDir::new().unwrap();
+        let cart = scratch.path().join("cart.p8");
+        std::fs::write(&cart, twins).unwrap();
+        picoeater(&["dump", "--dedupe-tabs", arg(&cart)]).unwrap();
+        let order = scratch.path().join(TAB_ORDER_FILE);
+        assert_eq!(
+            std::fs::read_to_string(&order).unwrap(),
+            "lib\nlib-again\tlib\n"
+        );
+
+        let err = add_tab(
+            scratch.path(),
+            "lib-again",
+            &b"y=2\n"[..],
+            false,
+            Codec::Utf8,
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(AddTabError::Exists(_))),
+            "{err}"
+        );
+        // Replacing it gives it its own file, in the same spot.
+        add_tab(
+            scratch.path(),
+            "lib-again",
+            &b"y=2\n"[..],
+            true,
+            Codec::Utf8,
+        )
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(&order).unwrap(), "lib\nlib-again\n");
+        picoeater(&["build", arg(&cart)]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&cart).unwrap(),
+            twins.replace(
+                "-- lib\nx=1\n-->8\n-- lib\nx=1",
+                "-- lib\nx=1\n-->8\n-- lib-again\ny=2"
+            )
+        );
+    }
+
+    #[test]
+    fn add_tab_waits_for_the_lock() {
+        use std::sync::mpsc;
+        use std::time::Duration;
+        let scratch = TempDir::new().unwrap();
+        let dir = scratch.path().to_path_buf();
+        let held = lock::lock_dir(&dir, || ()).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let adder = std::thread::spawn(move || {
+            add_tab(&dir, "main", &b"x=1\n"[..], false, Codec::Utf8).unwrap();
+            tx.send(()).unwrap();
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        assert!(!scratch.path().join(TAB_ORDER_FILE).exists());
+        drop(held);
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        adder.join().unwrap();
+        assert!(scratch.path().join("main.lua").exists());
+    }
+
+    #[test]
+    fn classic_and_tall_maps_both_round_trip_and_validate() {
+        let row = format!("{}\n", "0123456789abcdef".repeat(16));
+        for rows in [32, 64, sections::TALL_MAP_ROWS] {
+            let cart = format!("{CART}__map__\n{}", row.repeat(rows));
+            assert_eq!(
+                round_trip(cart.as_bytes(), &[], &["--validate"]),
+                cart.as_bytes(),
+                "{rows} rows"
+            );
+        }
+        let shown = Path::new("map.p8rsc");
+        let too_tall = row.repeat(sections::TALL_MAP_ROWS + 1);
+        let err = validate_rsc("map", &too_tall, shown).unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(BuildError::RowCount { .. })),
+            "{err}"
+        );
+        // Only the map gets to be tall.
+        let gfx_row = format!("{}\n", "0".repeat(128));
+        assert!(validate_rsc("gfx", &gfx_row.repeat(129), shown).is_err());
+    }
+
+    #[test]
+    fn ls_offsets_cover_each_section_body() {
+        let lines = [
+            "pico-8 cartridge // http://www.pico-8.com",
+            "version 41",
+            "__lua__",
+            "-- main",
+            "print(1)",
+            "-->8",
+            "x=2",
+            "__gfx__",
+            "0123",
+        ];
+        // (section, first line, line after the end) for each body.
+        let bodies = [
+            ("header", 0, 2),
+            ("lua:main", 3, 5),
+            ("lua", 6, 7),
+            ("gfx", 8, 9),
+        ];
+        for (eol, starts) in [
+            ("\n", [0, 42, 53, 61, 69, 78, 83, 87, 95, 100]),
+            ("\r\n", [0, 43, 55, 64, 73, 83, 89, 94, 103, 109]),
+        ] {
+            let cart: String = lines.iter().map(|line| format!("{line}{eol}")).collect();
+            let listed = list_sections(&cart, Codec::Utf8, true).unwrap();
+            let found: Vec<(&str, usize, usize)> = listed
+                .iter()
+                .map(|entry| {
+                    let (offset, length) = (entry.offset.unwrap(), entry.length.unwrap());
+                    (entry.section.as_str(), offset, offset + length)
+                })
+                .collect();
+            let expected: Vec<(&str, usize, usize)> = bodies
+                .iter()
+                .map(|&(section, first, end)| (section, starts[first], starts[end]))
+                .collect();
+            assert_eq!(found, expected, "{eol:?}");
+            let json = serde_json::to_value(&listed).unwrap();
+            assert_eq!(
+                json[1],
+                serde_json::json!({"section": "lua:main", "offset": starts[3], "length": starts[5] - starts[3]})
+            );
+
+            // Without --offsets, it's just the names.
+            let plain = serde_json::to_value(list_sections(&cart, Codec::Utf8, false).unwrap());
+            assert_eq!(plain.unwrap()[3], serde_json::json!({"section": "gfx"}));
+
+            let scratch = TempDir::new().unwrap();
+            let path = scratch.path().join("cart.p8");
+            std::fs::write(&path, &cart).unwrap();
+            picoeater(&["ls", "--offsets", "--json", arg(&path)]).unwrap();
+        }
+    }
+
+    #[test]
+    fn a_malformed_version_builds_with_the_default() {
+        let cart = CART.replace("version 41", "version beta");
+        let built = String::from_utf8(round_trip(cart.as_bytes(), &[], &[])).unwrap();
+        assert_eq!(
+            built,
+            CART.replace("version 41", &version_line(DEFAULT_P8_VERSION))
+        );
+    }
+
+    #[test]
+    fn tabs_named_like_section_markers_round_trip() {
+        for name in ["-->8", "__gfx__"] {
+            let cart = CART.replace("-- util", &format!("-- {name}"));
+            let scratch = TempDir::new().unwrap();
+            let path = scratch.path().join("cart.p8");
+            std::fs::write(&path, &cart).unwrap();
+            let report = picoeater(&["dump", arg(&path)]).unwrap();
+            assert_eq!(
+                report.warnings,
+                [format!(
+                    "Tab 1 is named '{name}', which looks like a section marker; writing it to unknown-01.lua instead."
+                )]
+            );
+            // The name comment stays put, so the build puts it back as-is.
+            assert_eq!(
+                std::fs::read_to_string(scratch.path().join("unknown-01.lua")).unwrap(),
+                format!("-- {name}\nfunction f() end\n")
+            );
+            picoeater(&["build", arg(&path)]).unwrap();
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), cart, "{name}");
+        }
+    }
+
+    #[test]
+    fn relative_carts_are_from_the_cwd_with_or_without_dir() {
+        let scratch = TempDir::new().unwrap();
+        let work = scratch.path().join("work");
+        let game = scratch.path().join("game");
+        std::fs::create_dir_all(&work).unwrap();
+        std::fs::create_dir_all(game.join("src")).unwrap();
+        std::fs::write(game.join("cart.p8"), CART).unwrap();
+        let env = Env {
+            cwd: work.clone(),
+            default_version: None,
+        };
+
+        // Without --dir, the components go next to the cart, not in the cwd.
+        picoeater_with(&env, &["dump", "../game/cart.p8"]).unwrap();
+        assert!(game.join("util.lua").exists());
+        // With it, --dir is from the cwd too, not from the cart.
+        picoeater_with(&env, &["dump", "../game/cart.p8", "--dir", "../game/src"]).unwrap();
+        assert!(game.join("src/util.lua").exists());
+        assert_eq!(std::fs::read_dir(&work).unwrap().count(), 0);
+
+        picoeater_with(&env, &["build", "../game/out.p8"]).unwrap();
+        assert_eq!(std::fs::read_to_string(game.join("out.p8")).unwrap(), CART);
+        std::fs::write(game.join("src/util.lua"), "-- util\n").unwrap();
+        picoeater_with(&env, &["build", "../game/cart.p8", "--dir", "../game/src"]).unwrap();
+        let built = std::fs::read_to_string(game.join("cart.p8")).unwrap();
+        assert!(built.contains("-->8\n-- util\n__gfx__"), "{built}");
+        assert_eq!(std::fs::read_dir(&work).unwrap().count(), 0);
+        assert!(!game.join("src/cart.p8").exists());
+    }
+
+    #[test]
+    fn the_default_version_env_var_fills_in_a_missing_version() {
+        let (scratch, cart) = dumped(CART);
+        std::fs::remove_file(scratch.path().join(P8_VERSION_FILE)).unwrap();
+        let env = Env {
+            default_version: Some("38".to_string()),
+            ..Env::from_process().unwrap()
+        };
+        picoeater_with(&env, &["build", arg(&cart)]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&cart).unwrap(),
+            CART.replace("version 41", "version 38")
+        );
+        // The flag still beats it, and a bad one is an error.
+        picoeater_with(&env, &["build", "--assume-version", "39", arg(&cart)]).unwrap();
+        assert!(std::fs::read_to_string(&cart)
+            .unwrap()
+            .contains("version 39\n"));
+        let bad = Env {
+            default_version: Some("beta".to_string()),
+            ..Env::from_process().unwrap()
+        };
+        let err = picoeater_with(&bad, &["build", arg(&cart)]).unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(BuildError::FallbackVersion(_))),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn a_failed_validation_leaves_the_old_cart_alone() {
+        let (scratch, cart) = dumped(CART);
+        let before = std::fs::read(&cart).unwrap();
+        std::fs::write(scratch.path().join("gfx.p8rsc"), "0000\n").unwrap();
+        let err = picoeater(&["build", "--validate", arg(&cart)]).unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(BuildError::RowWidth { .. })),
+            "{err}"
+        );
+        assert_eq!(std::fs::read(&cart).unwrap(), before);
+
+        // Same for a JSON build.
+        let json = scratch.path().join("cart.json");
+        std::fs::write(
+            &json,
+            r#"{"version": null, "header": "", "tabs": [],
+                "resources": [{"kind": "gfx", "body": "0000\n"}]}"#,
+        )
+        .unwrap();
+        let args = ["build", "--validate", "--from-json", arg(&json), arg(&cart)];
+        let err = picoeater(&args).unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(BuildError::RowWidth { .. })),
+            "{err}"
+        );
+        assert_eq!(std::fs::read(&cart).unwrap(), before);
+    }
+
+    #[test]
+    fn validate_reads_resources_with_the_build_encoding() {
+        let (scratch, cart) = dumped(CART);
+        // A Latin-1 byte isn't UTF-8 at all, and it's one character wide
+        // once it's in the cart.
+        let mut row = vec![b'0'; 127];
+        row.extend(b"\xe9\n");
+        std::fs::write(scratch.path().join("gfx.p8rsc"), &row).unwrap();
+        let cart_arg = arg(&cart);
+        picoeater(&["build", "--validate", "--encoding", "latin1", cart_arg]).unwrap();
+        let built = std::fs::read_to_string(&cart).unwrap();
+        assert!(built.ends_with(&format!("{}\u{e9}\n", "0".repeat(127))));
+    }
+}
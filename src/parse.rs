@@ -0,0 +1,702 @@
+use std::io::{BufRead, Read};
+use std::ops::Range;
+
+// Okay, so http://pico8wiki.com/index.php?title=P8FileFormat
+// - I'm gonna handle multiple lua files, and preserve the order
+//   they were found in the p8 file if applicable.
+// - I'm not gonna handle multiple gfx etc. sections. Don't do that.
+// - I'm gonna treat the sections non-exhaustively; not positive a new section
+//   hasn't been added since that write-up. But anything after the defined
+//   order goes randomly last.
+//
+// Here's the format:
+// pico-8 cartridge // http://www.pico-8.com
+// version 41
+// __lua__
+// -- dr chaos
+// ...
+// -->8
+// -- splash screen
+// ...
+// __gfx__
+// ...
+//
+// ...and eventually it ends.
+//
+// > The sections appear in the following order: a header, the Lua code (__lua__),
+// > the spritesheet (__gfx__), the sprite flags (__gff__), the cartridge label
+// > (__label__), the map (__map__), sound effects (__sfx__), and music patterns
+// > (__music__). These sections are described in more detail below.
+
+/// The first line of every .p8 file, exactly as PICO-8 writes it.
+pub const CART_HEADER: &str = "pico-8 cartridge // http://www.pico-8.com";
+
+/// The format version to write when a cart doesn't say.
+pub const DEFAULT_P8_VERSION: &str = "41";
+
+/// The header's version line, exactly as PICO-8 writes it. (The parser reads
+/// it back, plus some sloppier variations.)
+pub fn version_line(version: &str) -> String {
+    format!("version {}", version)
+}
+
+/// One chunk of a .p8 file. Bodies are whole lines, each ending in "\n"
+/// (whatever line endings the cart originally used).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Section {
+    /// Everything before `__lua__`.
+    Header {
+        /// The cart's format version, if there was a well-formed version line.
+        version: Option<String>,
+        body: String,
+    },
+    /// One tab of lua code.
+    LuaTab {
+        /// The name from the tab's first-line comment, if it has one.
+        name: Option<String>,
+        body: String,
+    },
+    /// A `__kind__` section and its contents (not including the tag line).
+    Resource { kind: String, body: String },
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ParseError {
+    #[error("Somehow never got out of Init; either a bug or a corrupt .p8 file")]
+    EndInInit,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// One step the parser took, for `parse_sections_traced`: the line that
+/// did it, and the state before and after.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transition {
+    /// 1-based line number.
+    pub line: usize,
+    pub text: String,
+    pub from: String,
+    pub to: String,
+}
+
+impl std::fmt::Display for Transition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Long lines (like a row of gfx) just get their start shown.
+        let mut text: String = self.text.chars().take(40).collect();
+        if text.len() < self.text.len() {
+            text.push_str("...");
+        }
+        write!(
+            f,
+            "line {}: {} -> {}  ({:?})",
+            self.line, self.from, self.to, text
+        )
+    }
+}
+
+enum ReadState {
+    Init { body: String },
+    // LuaStart gets the script name on the next line, bc it goes "scissors \n comment".
+    // (Or the next non-blank line; it hangs onto any blank ones until then.)
+    // `scissors` says we got here by a `-->8` rather than a `__lua__`, so
+    // there's a tab coming even if it turns out empty.
+    LuaStart { blanks: String, scissors: bool },
+    Lua { name: Option<String>, body: String },
+    // A resource section's body can be empty, even when it's the very last
+    // thing in the cart.
+    Rsc { kind: String, body: String },
+}
+
+impl ReadState {
+    /// A short description, for tracing.
+    fn label(&self) -> String {
+        match self {
+            ReadState::Init { .. } => "Init".to_string(),
+            ReadState::LuaStart { .. } => "LuaStart".to_string(),
+            ReadState::Lua { name, .. } => {
+                format!("Lua({})", name.as_deref().unwrap_or("anonymous"))
+            }
+            ReadState::Rsc { kind, .. } => format!("Rsc({})", kind),
+        }
+    }
+}
+
+/// Split a .p8 cart into its sections, without touching the filesystem.
+pub fn parse_sections(reader: impl BufRead) -> Result<Vec<Section>, ParseError> {
+    let found = parse_sections_with_spans(reader)?;
+    Ok(found.into_iter().map(|(section, _)| section).collect())
+}
+
+/// Same as `parse_sections`, but calls `trace` every time the parser changes
+/// state or finishes a section. Handy for working out where a weird cart
+/// went sideways.
+pub fn parse_sections_traced(
+    reader: impl BufRead,
+    mut trace: impl FnMut(&Transition),
+) -> Result<Vec<Section>, ParseError> {
+    let found = parse_inner(reader, Some(&mut trace))?;
+    Ok(found.into_iter().map(|(section, _)| section).collect())
+}
+
+/// Same as `parse_sections`, but also says where each section's body sits in
+/// the original bytes (line endings and all). The span starts just after the
+/// section's tag line (or the `-->8` for a tab), and runs up to the next one.
+pub fn parse_sections_with_spans(
+    reader: impl BufRead,
+) -> Result<Vec<(Section, Range<usize>)>, ParseError> {
+    parse_inner(reader, None)
+}
+
+fn parse_inner(
+    mut reader: impl BufRead,
+    trace: Option<&mut dyn FnMut(&Transition)>,
+) -> Result<Vec<(Section, Range<usize>)>, ParseError> {
+    let mut first = String::new();
+    reader.read_line(&mut first)?;
+    // A cart with classic Mac line endings (a bare \r, no \n anywhere) comes
+    // through as one giant line. Swapping each \r for \n keeps every byte
+    // in the same spot, so the spans still line up.
+    if !first.ends_with('\n') && first.contains('\r') {
+        let text = first.replace('\r', "\n");
+        return parse_lines(text.as_bytes(), trace);
+    }
+    parse_lines(first.as_bytes().chain(reader), trace)
+}
+
+fn parse_lines(
+    mut reader: impl BufRead,
+    mut trace: Option<&mut dyn FnMut(&Transition)>,
+) -> Result<Vec<(Section, Range<usize>)>, ParseError> {
+    let mut sections = Spans::default();
+    let mut state = ReadState::Init {
+        body: String::new(),
+    };
+    let mut version = None;
+    let mut raw = String::new();
+    let mut line_end = 0;
+    let mut line_number = 0;
+
+    loop {
+        raw.clear();
+        let read = reader.read_line(&mut raw)?;
+        if read == 0 {
+            break;
+        }
+        let line_start = line_end;
+        line_end += read;
+        line_number += 1;
+        sections.line_start = line_start;
+        sections.line_end = line_end;
+        // Same as BufRead::lines(): lose the \n, then any \r before it.
+        let line = raw.strip_suffix('\n').unwrap_or(&raw);
+        let line = line.strip_suffix('\r').unwrap_or(line).to_string();
+        // Only bother working out labels if somebody's listening.
+        let before = trace
+            .as_ref()
+            .map(|_| (state.label(), sections.found.len()));
+        match &mut state {
+            ReadState::Init { body } => {
+                // Get version from the header, and wait for the lua section.
+                if line == "__lua__" {
+                    sections.push(Section::Header {
+                        version: version.take(),
+                        body: std::mem::take(body),
+                    });
+                    state = ReadState::LuaStart {
+                        blanks: String::new(),
+                        scissors: false,
+                    };
+                } else if let Some(rsc_kind) = rsc_tag(&line) {
+                    // Out-of-order cart with resources before the lua. Don't drop
+                    // them on the floor; the lua can come along later.
+                    sections.push(Section::Header {
+                        version: version.take(),
+                        body: std::mem::take(body),
+                    });
+                    state = ReadState::Rsc {
+                        kind: rsc_kind.to_string(),
+                        body: String::new(),
+                    };
+                } else {
+                    if let Some(ver) = version_tag(&line) {
+                        version = Some(ver.to_string());
+                    }
+                    push_line(body, &line);
+                }
+            }
+            ReadState::LuaStart { blanks, scissors } => {
+                // An empty lua section goes straight into the resources (or
+                // the next lua section, in a weird cart), and doesn't get a
+                // tab at all.
+                let next = if line == "__lua__" {
+                    Some(ReadState::LuaStart {
+                        blanks: String::new(),
+                        scissors: false,
+                    })
+                } else {
+                    rsc_tag(&line).map(|rsc_kind| ReadState::Rsc {
+                        kind: rsc_kind.to_string(),
+                        body: String::new(),
+                    })
+                };
+                if let Some(next) = next {
+                    // ...but after a `-->8` that's an empty last tab, same as
+                    // at the end of the file. And blank lines are a tab either way.
+                    if !blanks.is_empty() || *scissors {
+                        sections.push(Section::LuaTab {
+                            name: None,
+                            body: std::mem::take(blanks),
+                        });
+                    }
+                    state = next;
+                    sections.start = line_end;
+                } else if line == "-->8" {
+                    // A tab of nothing but blank lines, or nothing at all.
+                    // (PICO-8 writes `-->8` twice in a row for an empty tab
+                    // in the middle; that's not a tab named ">8".)
+                    sections.push(Section::LuaTab {
+                        name: None,
+                        body: std::mem::take(blanks),
+                    });
+                    *scissors = true;
+                } else if line.trim().is_empty() {
+                    // Blank lines before the name comment don't stop it being
+                    // the name; save them up and keep looking.
+                    push_line(blanks, &line);
+                } else {
+                    // Do we have a script name from an initial comment?
+                    let name = lua_tag(&line).map(str::to_string);
+                    let mut body = std::mem::take(blanks);
+                    // Keep that initial line so we don't drop it!
+                    push_line(&mut body, &line);
+                    state = ReadState::Lua { name, body };
+                }
+            }
+            ReadState::Lua { name, body } => {
+                if line == "-->8" || line == "__lua__" {
+                    // we're done!! NEXT, (A second `__lua__` is a weird
+                    // cart, but it's still a new tab.)
+                    sections.push(Section::LuaTab {
+                        name: name.take(),
+                        body: std::mem::take(body),
+                    });
+                    state = ReadState::LuaStart {
+                        blanks: String::new(),
+                        scissors: line == "-->8",
+                    };
+                } else if let Some(rsc_kind) = rsc_tag(&line) {
+                    // we're done! Next stop, resourceville
+                    sections.push(Section::LuaTab {
+                        name: name.take(),
+                        body: std::mem::take(body),
+                    });
+                    state = ReadState::Rsc {
+                        kind: rsc_kind.to_string(),
+                        body: String::new(),
+                    };
+                } else {
+                    // normal line.
+                    push_line(body, &line);
+                }
+            }
+            ReadState::Rsc { kind, body } => {
+                if line == "__lua__" {
+                    // A late lua section, in an out-of-order cart.
+                    sections.push(Section::Resource {
+                        kind: std::mem::take(kind),
+                        body: std::mem::take(body),
+                    });
+                    state = ReadState::LuaStart {
+                        blanks: String::new(),
+                        scissors: false,
+                    };
+                } else if let Some(rsc_kind) = rsc_tag(&line) {
+                    // we're done. next!
+                    sections.push(Section::Resource {
+                        kind: std::mem::take(kind),
+                        body: std::mem::take(body),
+                    });
+                    state = ReadState::Rsc {
+                        kind: rsc_kind.to_string(),
+                        body: String::new(),
+                    };
+                } else {
+                    // normal line.
+                    push_line(body, &line);
+                }
+            }
+        }
+        if let (Some(trace), Some((from, found))) = (trace.as_mut(), before) {
+            let to = state.label();
+            if to != from || sections.found.len() != found {
+                trace(&Transition {
+                    line: line_number,
+                    text: line,
+                    from,
+                    to,
+                });
+            }
+        }
+    }
+    // Wrap up whatever we were in the middle of once we've consumed the whole file.
+    match state {
+        ReadState::Init { .. } => return Err(ParseError::EndInInit),
+        ReadState::LuaStart { blanks, scissors } => {
+            // A cart can end right after a `-->8`, which PICO-8 itself does
+            // if the last tab is empty; that's an empty tab. (Ending right
+            // after `__lua__` is just an empty lua section, so no tab.)
+            if !blanks.is_empty() || scissors {
+                sections.push_last(Section::LuaTab {
+                    name: None,
+                    body: blanks,
+                });
+            }
+        }
+        ReadState::Lua { name, body } => sections.push_last(Section::LuaTab { name, body }),
+        ReadState::Rsc { kind, body } => sections.push_last(Section::Resource { kind, body }),
+    }
+    Ok(sections.found)
+}
+
+/// Keeps track of where sections start and end while parsing.
+#[derive(Default)]
+struct Spans {
+    found: Vec<(Section, Range<usize>)>,
+    /// Where the section we're in the middle of started.
+    start: usize,
+    /// Where the current line starts and ends.
+    line_start: usize,
+    line_end: usize,
+}
+
+impl Spans {
+    /// Finish a section at the current line, which is the tag line for the
+    /// next one (so that one starts after it).
+    fn push(&mut self, section: Section) {
+        self.found.push((section, self.start..self.line_start));
+        self.start = self.line_end;
+    }
+
+    /// Finish the last section at the end of the file.
+    fn push_last(&mut self, section: Section) {
+        self.found.push((section, self.start..self.line_end));
+    }
+}
+
+fn push_line(body: &mut String, line: &str) {
+    body.push_str(line);
+    body.push('\n');
+}
+
+/// If a line is a section tag like `__gfx__`, returns the kind (`gfx`).
+pub fn rsc_tag(line: &str) -> Option<&str> {
+    // (Careful not to slice by byte index here; a line full of PICO-8 glyphs
+    // has plenty of spots that aren't char boundaries.)
+    let rest = line.strip_prefix("__")?.strip_suffix("__")?;
+    // I'm gonna do a real fast and loose one here so I don't have to take a regexp
+    // dep or hardcode the resource kinds.
+    // basically I want it to be one "word" in there.
+    if !rest.is_empty() && !rest.contains([' ', '=', '.']) {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+/// If a line is a `-- comment` (or a one-line `--[[ comment ]]`), returns the
+/// comment text, for use as a tab name.
+// Note that this is only a valid question on the FIRST line of a lua
+// file (blank lines aside), so only the LuaStart state can use it.
+pub fn lua_tag(line: &str) -> Option<&str> {
+    if let Some(block) = line.strip_prefix("--[[") {
+        // A block comment only counts if it closes on the same line; the
+        // start of a long multi-line comment isn't a name.
+        let rest = block.trim_end().strip_suffix("]]")?.trim();
+        return if rest.is_empty() { None } else { Some(rest) };
+    }
+    let rest = line.strip_prefix("--")?.trim();
+    if rest.is_empty() {
+        None
+    } else {
+        Some(rest)
+    }
+}
+
+/// The name from a whole tab's text: the `-- name` comment on its first
+/// non-blank line, if that's what it is. Same rule dump uses.
+pub fn tab_name(text: &str) -> Option<&str> {
+    text.lines()
+        .find(|line| !line.trim().is_empty())
+        .and_then(lua_tag)
+}
+
+/// If a line is a PICO-8 `#include` directive, returns the path it includes.
+pub fn include_tag(line: &str) -> Option<&str> {
+    let rest = line.trim().strip_prefix("#include")?;
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let path = rest.trim();
+    if path.is_empty() {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+// Only matches a header line that's `version <number>`, give or take some
+// whitespace, or `version:<number>` like some exporters write. Anything else
+// in the header gets ignored, and the build falls back to the default version.
+fn version_tag(line: &str) -> Option<&str> {
+    let rest = line.trim().strip_prefix("version")?;
+    let ver = match rest.trim_start().strip_prefix(':') {
+        Some(after_colon) => after_colon,
+        None if rest.starts_with(char::is_whitespace) => rest,
+        None => return None,
+    };
+    let ver = ver.trim();
+    if !ver.is_empty() && ver.bytes().all(|b| b.is_ascii_digit()) {
+        Some(ver)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tabs(cart: &str) -> Vec<(Option<String>, String)> {
+        parse_sections(cart.as_bytes())
+            .unwrap()
+            .into_iter()
+            .filter_map(|section| match section {
+                Section::LuaTab { name, body } => Some((name, body)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    const HEADER: &str = "pico-8 cartridge // http://www.pico-8.com\nversion 41\n";
+
+    #[test]
+    fn parses_a_cart_into_sections() {
+        let cart = format!(
+            "{HEADER}__lua__\n-- main\nprint(1)\n-->8\nx=1\n__gfx__\n0000\n1111\n__music__\n00 41424344\n"
+        );
+        assert_eq!(
+            parse_sections(cart.as_bytes()).unwrap(),
+            vec![
+                Section::Header {
+                    version: Some("41".to_string()),
+                    body: HEADER.to_string(),
+                },
+                Section::LuaTab {
+                    name: Some("main".to_string()),
+                    body: "-- main\nprint(1)\n".to_string(),
+                },
+                Section::LuaTab {
+                    name: None,
+                    body: "x=1\n".to_string(),
+                },
+                Section::Resource {
+                    kind: "gfx".to_string(),
+                    body: "0000\n1111\n".to_string(),
+                },
+                Section::Resource {
+                    kind: "music".to_string(),
+                    body: "00 41424344\n".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn resources_before_the_lua_are_kept() {
+        let cart = format!("{HEADER}__gfx__\n0000\n__lua__\n-- main\n__map__\n11\n");
+        assert_eq!(
+            parse_sections(cart.as_bytes()).unwrap(),
+            vec![
+                Section::Header {
+                    version: Some("41".to_string()),
+                    body: HEADER.to_string(),
+                },
+                Section::Resource {
+                    kind: "gfx".to_string(),
+                    body: "0000\n".to_string(),
+                },
+                Section::LuaTab {
+                    name: Some("main".to_string()),
+                    body: "-- main\n".to_string(),
+                },
+                Section::Resource {
+                    kind: "map".to_string(),
+                    body: "11\n".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn block_comments_can_name_tabs() {
+        assert_eq!(lua_tag("--[[ util ]]"), Some("util"));
+        assert_eq!(lua_tag("--[[util]]  "), Some("util"));
+        // The start of a long comment isn't a name, and neither is nothing.
+        assert_eq!(lua_tag("--[[ docs"), None);
+        assert_eq!(lua_tag("--[[ ]]"), None);
+        assert_eq!(lua_tag("--[[ util ]] x=1"), None);
+
+        let cart = format!("{HEADER}__lua__\n--[[ util ]]\nx=1\n-->8\n--[[\nnotes\n]]\n");
+        assert_eq!(
+            tabs(&cart),
+            vec![
+                (Some("util".to_string()), "--[[ util ]]\nx=1\n".to_string()),
+                (None, "--[[\nnotes\n]]\n".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_cart_without_a_version_still_has_lua() {
+        let cart = format!("{CART_HEADER}\n__lua__\n-- main\nx=1\n");
+        assert_eq!(
+            parse_sections(cart.as_bytes()).unwrap(),
+            vec![
+                Section::Header {
+                    version: None,
+                    body: format!("{CART_HEADER}\n"),
+                },
+                Section::LuaTab {
+                    name: Some("main".to_string()),
+                    body: "-- main\nx=1\n".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn version_lines_can_be_written_a_few_ways() {
+        let version = |line: &str| {
+            let cart = format!("{CART_HEADER}\n{line}\n__lua__\nx=1\n");
+            match parse_sections(cart.as_bytes()).unwrap().remove(0) {
+                Section::Header { version, .. } => version,
+                other => panic!("not a header: {other:?}"),
+            }
+        };
+        for line in [
+            "version 41",
+            "version: 41",
+            "version:41",
+            "  version \t 41  ",
+        ] {
+            assert_eq!(version(line).as_deref(), Some("41"), "{line:?}");
+        }
+        // Anything else is just a header line, and the build uses the default.
+        for line in ["version beta", "version41", "version:", "versions 41"] {
+            assert_eq!(version(line), None, "{line:?}");
+        }
+    }
+
+    #[test]
+    fn names_can_come_after_blank_lines() {
+        let cart = format!("{HEADER}__lua__\n\n\n-- main\nx=1\n-->8\n \ny=2\n-- not a name\n");
+        assert_eq!(
+            tabs(&cart),
+            vec![
+                (Some("main".to_string()), "\n\n-- main\nx=1\n".to_string()),
+                (None, " \ny=2\n-- not a name\n".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_resource_with_no_body_at_the_end_is_empty() {
+        let cart = format!("{HEADER}__lua__\n-- main\n__music__\n");
+        assert_eq!(
+            parse_sections(cart.as_bytes()).unwrap().last(),
+            Some(&Section::Resource {
+                kind: "music".to_string(),
+                body: String::new(),
+            })
+        );
+    }
+
+    #[test]
+    fn cr_only_carts_split_into_lines() {
+        let lf = format!("{HEADER}__lua__\n-- main\nx=1\n-->8\n-- util\n__gfx__\n0000\n");
+        let cr = lf.replace('\n', "\r");
+        assert_eq!(tabs(&cr).len(), 2);
+        assert_eq!(
+            parse_sections(cr.as_bytes()).unwrap(),
+            parse_sections(lf.as_bytes()).unwrap()
+        );
+    }
+
+    #[test]
+    fn dangling_scissors_at_the_end_is_an_empty_tab() {
+        let cart = format!("{HEADER}__lua__\n-- main\nprint(1)\n-->8\n");
+        assert_eq!(
+            tabs(&cart),
+            vec![
+                (Some("main".to_string()), "-- main\nprint(1)\n".to_string()),
+                (None, String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn dangling_scissors_before_resources_is_an_empty_tab() {
+        let cart = format!("{HEADER}__lua__\n-- main\n-->8\n__gfx__\n0000\n");
+        assert_eq!(
+            tabs(&cart),
+            vec![
+                (Some("main".to_string()), "-- main\n".to_string()),
+                (None, String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_second_lua_section_just_has_more_tabs() {
+        let cart = format!("{HEADER}__lua__\n-- a\n__lua__\n-- b\n__lua__\n__gfx__\n");
+        assert_eq!(
+            tabs(&cart),
+            vec![
+                (Some("a".to_string()), "-- a\n".to_string()),
+                (Some("b".to_string()), "-- b\n".to_string()),
+            ]
+        );
+        let kinds: Vec<String> = parse_sections(cart.as_bytes())
+            .unwrap()
+            .into_iter()
+            .filter_map(|section| match section {
+                Section::Resource { kind, .. } => Some(kind),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(kinds, ["gfx"]);
+    }
+
+    #[test]
+    fn empty_lua_section_has_no_tabs() {
+        assert_eq!(tabs(&format!("{HEADER}__lua__\n")), vec![]);
+        assert_eq!(tabs(&format!("{HEADER}__lua__\n__gfx__\n0000\n")), vec![]);
+    }
+
+    #[test]
+    fn empty_middle_tab_does_not_swallow_the_next_one() {
+        let cart = format!("{HEADER}__lua__\n-- a\n-->8\n-->8\n-- c\nx=1\n");
+        assert_eq!(
+            tabs(&cart),
+            vec![
+                (Some("a".to_string()), "-- a\n".to_string()),
+                (None, String::new()),
+                (Some("c".to_string()), "-- c\nx=1\n".to_string()),
+            ]
+        );
+        // Blank lines between the scissors are still their own tab, too.
+        let cart = format!("{HEADER}__lua__\n-- a\n-->8\n\n-->8\n-- c\n");
+        assert_eq!(tabs(&cart)[1], (None, "\n".to_string()));
+        assert_eq!(tabs(&cart)[2].0.as_deref(), Some("c"));
+    }
+}
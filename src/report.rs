@@ -0,0 +1,78 @@
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+
+use crate::Finding;
+
+/// Everything worth reporting about a run: warnings, doctor findings, and a
+/// summary of what happened. The console gets all of this as it happens; with
+/// `--report-file`, it also gets saved once we're done, as JSON if the
+/// filename ends in `.json` and plain text otherwise.
+#[derive(Serialize, Debug, Default)]
+pub struct Report {
+    pub command: String,
+    pub ok: bool,
+    pub error: Option<String>,
+    pub warnings: Vec<String>,
+    pub findings: Vec<Finding>,
+    pub notes: Vec<String>,
+}
+
+impl Report {
+    pub fn new(command: &str) -> Self {
+        Self {
+            command: command.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Print a warning (to stderr, so it stays out of anything piped), and
+    /// keep it for the report.
+    pub fn warn(&mut self, msg: &str) {
+        eprintln!("WARNING: {}", msg);
+        self.warnings.push(msg.to_string());
+    }
+
+    /// Keep a warning for the report that's already been printed some other way.
+    pub fn add_warning(&mut self, msg: String) {
+        self.warnings.push(msg);
+    }
+
+    /// Keep a summary line for the report. (Not printed.)
+    pub fn note(&mut self, msg: String) {
+        self.notes.push(msg);
+    }
+
+    /// Wrap up with the command's result, and write the report file.
+    pub fn finish(mut self, result: &anyhow::Result<()>, path: &Path) -> anyhow::Result<()> {
+        self.ok = result.is_ok();
+        self.error = result.as_ref().err().map(|e| format!("{:#}", e));
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::to_writer_pretty(&mut writer, &self)?;
+            writeln!(writer)?;
+        } else {
+            writeln!(
+                writer,
+                "picoeater {}: {}",
+                self.command,
+                if self.ok { "ok" } else { "failed" }
+            )?;
+            if let Some(error) = &self.error {
+                writeln!(writer, "ERROR: {}", error)?;
+            }
+            for warning in self.warnings.iter() {
+                writeln!(writer, "WARNING: {}", warning)?;
+            }
+            for finding in self.findings.iter() {
+                writeln!(writer, "PROBLEM: {}", finding.problem)?;
+                writeln!(writer, "    Fix: {}", finding.fix)?;
+            }
+            for note in self.notes.iter() {
+                writeln!(writer, "{}", note)?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
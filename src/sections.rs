@@ -0,0 +1,234 @@
+// What we know about the shapes of PICO-8's resource sections. Every row of
+// these is a fixed-width run of hex digits, except music, which has a flags
+// byte and a space in front, and the label, whose pixels can use all 32
+// colors (so its digits run from 0 to v, not 0 to f).
+
+/// The shape of one known resource section.
+#[derive(Debug)]
+pub struct SectionInfo {
+    pub kind: &'static str,
+    /// How many characters wide each row is.
+    pub width: usize,
+    /// The most rows the section can have in its usual layout. (PICO-8
+    /// leaves off trailing rows that are all zeroes, so there can be fewer.)
+    pub rows: usize,
+    /// Whether the rows are nothing but hex digits.
+    pub hex: bool,
+    /// The most rows in the section's tall layout, if it has one. (That's
+    /// just the map: newer carts can store a bigger one.)
+    pub tall_rows: Option<usize>,
+}
+
+/// How many rows a tall map can have: 64KB of map, 128 bytes a row.
+pub const TALL_MAP_ROWS: usize = 512;
+
+impl SectionInfo {
+    /// The most rows the section can have, in whichever layout it uses.
+    pub fn max_rows(&self) -> usize {
+        self.tall_rows.unwrap_or(self.rows)
+    }
+
+    /// Whether a section with this many rows is using its tall layout.
+    /// There's no marker for it in the cart; the row count is all there is.
+    pub fn is_tall(&self, rows: usize) -> bool {
+        self.tall_rows.is_some() && rows > self.rows
+    }
+}
+
+pub const KNOWN_SECTIONS: [SectionInfo; 6] = [
+    SectionInfo {
+        kind: "gfx",
+        width: 128,
+        rows: 128,
+        hex: true,
+        tall_rows: None,
+    },
+    SectionInfo {
+        kind: "gff",
+        width: 256,
+        rows: 2,
+        hex: true,
+        tall_rows: None,
+    },
+    SectionInfo {
+        kind: "label",
+        width: 128,
+        rows: 128,
+        hex: false,
+        tall_rows: None,
+    },
+    SectionInfo {
+        kind: "map",
+        width: 256,
+        rows: 32,
+        hex: true,
+        tall_rows: Some(TALL_MAP_ROWS),
+    },
+    SectionInfo {
+        kind: "sfx",
+        width: 168,
+        rows: 64,
+        hex: true,
+        tall_rows: None,
+    },
+    SectionInfo {
+        kind: "music",
+        width: 11,
+        rows: 64,
+        hex: false,
+        tall_rows: None,
+    },
+];
+
+/// Look up a section kind, if it's one we know. Banked kinds like `sfx1`
+/// have the same shape as their base kind.
+pub fn lookup(kind: &str) -> Option<&'static SectionInfo> {
+    let (base, _) = split_bank(kind);
+    KNOWN_SECTIONS.iter().find(|info| info.kind == base)
+}
+
+/// Split a numeric bank suffix off a section kind, so `sfx1` comes back as
+/// `("sfx", Some(1))`. PICO-8 itself only has one of each section, but some
+/// other tools split sfx and music into banks this way. Kinds without a
+/// suffix (or that are nothing BUT digits) come back whole.
+pub fn split_bank(kind: &str) -> (&str, Option<u32>) {
+    let base = kind.trim_end_matches(|c: char| c.is_ascii_digit());
+    if base.is_empty() || base.len() == kind.len() {
+        return (kind, None);
+    }
+    match kind[base.len()..].parse() {
+        Ok(bank) => (base, Some(bank)),
+        // Too many digits to be a bank number; whatever it is, leave it be.
+        Err(_) => (kind, None),
+    }
+}
+
+/// Decode a hex section body into raw bytes, two digits per byte. Returns
+/// None unless re-encoding the bytes would give back the exact same text
+/// (so no uppercase, no ragged rows, no stray whitespace).
+pub fn hex_to_bytes(body: &str, width: usize) -> Option<Vec<u8>> {
+    if !width.is_multiple_of(2) {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(body.len() / 2);
+    for line in body.lines() {
+        if line.len() != width {
+            return None;
+        }
+        for pair in line.as_bytes().chunks(2) {
+            let hi = hex_digit(pair[0])?;
+            let lo = hex_digit(pair[1])?;
+            bytes.push(hi << 4 | lo);
+        }
+    }
+    if bytes_to_hex(&bytes, width) == body {
+        Some(bytes)
+    } else {
+        None
+    }
+}
+
+/// Tidy up a hand-edited hex section: lowercase the digits and drop stray
+/// spaces and tabs, without changing a single value. Returns None if that's
+/// not enough to make every row exactly `width` hex digits, since fixing a
+/// ragged row or a non-hex character would mean guessing at the data.
+pub fn canonicalize_hex(body: &str, width: usize) -> Option<String> {
+    let mut text = String::with_capacity(body.len());
+    for line in body.lines() {
+        let row: String = line
+            .chars()
+            .filter(|c| !matches!(c, ' ' | '\t'))
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        if row.len() != width || !row.bytes().all(|b| hex_digit(b).is_some()) {
+            return None;
+        }
+        text.push_str(&row);
+        text.push('\n');
+    }
+    Some(text)
+}
+
+/// Encode raw bytes as lowercase hex rows of the given width, each ending in "\n".
+pub fn bytes_to_hex(bytes: &[u8], width: usize) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut text = String::with_capacity(bytes.len() * 2 + bytes.len() * 2 / width.max(1) + 1);
+    for row in bytes.chunks((width / 2).max(1)) {
+        for b in row {
+            text.push(DIGITS[(b >> 4) as usize] as char);
+            text.push(DIGITS[(b & 0xf) as usize] as char);
+        }
+        text.push('\n');
+    }
+    text
+}
+
+// Lowercase only; PICO-8 never writes uppercase, and we couldn't put it back.
+fn hex_digit(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_is_not_hex() {
+        // Labels use the extended palette, so a `g` is a real color there.
+        assert!(!lookup("label").unwrap().hex);
+        assert!(lookup("gfx").unwrap().hex);
+        assert_eq!(hex_to_bytes("0g\n", 2), None);
+        assert_eq!(canonicalize_hex("0G\n", 2), None);
+    }
+
+    #[test]
+    fn hex_round_trips_through_bytes() {
+        let body = "0123456789abcdef\nfedcba9876543210\n";
+        let bytes = hex_to_bytes(body, 16).unwrap();
+        assert_eq!(bytes[0], 0x01);
+        assert_eq!(bytes_to_hex(&bytes, 16), body);
+        // Uppercase wouldn't come back the same, so it doesn't count.
+        assert_eq!(hex_to_bytes("0123456789ABCDEF\n", 16), None);
+    }
+
+    #[test]
+    fn numbered_kinds_split_into_banks() {
+        assert_eq!(split_bank("sfx"), ("sfx", None));
+        assert_eq!(split_bank("sfx0"), ("sfx", Some(0)));
+        assert_eq!(split_bank("sfx12"), ("sfx", Some(12)));
+        assert_eq!(split_bank("123"), ("123", None));
+        assert_eq!(split_bank("sfx99999999999"), ("sfx99999999999", None));
+    }
+
+    #[test]
+    fn canonical_hex_only_changes_the_look() {
+        let messy = "00AB cdEF\n\t0123 4567\r\n";
+        let tidy = canonicalize_hex(messy, 8).unwrap();
+        assert_eq!(tidy, "00abcdef\n01234567\n");
+        assert_eq!(
+            hex_to_bytes(&tidy, 8).unwrap(),
+            [0x00, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67]
+        );
+        assert_eq!(canonicalize_hex(&tidy, 8).unwrap(), tidy);
+        // Too short, too long, or not hex: hands off.
+        assert_eq!(canonicalize_hex("00ab cde\n", 8), None);
+        assert_eq!(canonicalize_hex("00abcdef0\n", 8), None);
+        assert_eq!(canonicalize_hex("00abcdeg\n", 8), None);
+    }
+
+    #[test]
+    fn maps_can_be_tall() {
+        let map = lookup("map").unwrap();
+        assert!(!map.is_tall(32));
+        assert!(map.is_tall(33));
+        assert_eq!(map.max_rows(), TALL_MAP_ROWS);
+        // Nothing else has a tall layout.
+        let gfx = lookup("gfx").unwrap();
+        assert!(!gfx.is_tall(200));
+        assert_eq!(gfx.max_rows(), 128);
+    }
+}